@@ -0,0 +1,361 @@
+use log::{debug, info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    io::{self, Read, Write},
+    mem,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use zeroize::Zeroizing;
+
+/// Frames larger than this are rejected instead of allocated.
+const MAX_FRAME_SZ: u32 = 1 << 16;
+
+/// How long either end gets to send each message, or to read each one, before the connection is
+/// dropped, so a stuck client doesn't tie up a thread and a stuck agent doesn't hang a mount.
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the agent listens by default: the user's runtime directory if there is one, which only
+/// they can get into, or `/run`, which only root can.
+pub fn default_socket() -> PathBuf {
+    let dir = env::var_os("XDG_RUNTIME_DIR").unwrap_or_else(|| "/run".into());
+    Path::new(&dir).join("sdbtreefs-agent.sock")
+}
+
+/// A request from an [`AgentEnclave`](crate::enclave::AgentEnclave) to a key agent.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    Get {
+        volume: String,
+    },
+    Rotate {
+        volume: String,
+        record: Zeroizing<Vec<u8>>,
+    },
+}
+
+impl Request {
+    fn volume(&self) -> &str {
+        match self {
+            Self::Get { volume } | Self::Rotate { volume, .. } => volume,
+        }
+    }
+
+    fn action(&self) -> &'static str {
+        match self {
+            Self::Get { .. } => "get",
+            Self::Rotate { .. } => "rotate",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Record(Option<Zeroizing<Vec<u8>>>),
+    Rotated,
+    Error(String),
+}
+
+/// Sends a message as `[len: u32][bincode]`. Messages can hold records, so buffers are wiped.
+pub(crate) fn send<T: Serialize>(stream: &mut UnixStream, msg: &T) -> io::Result<()> {
+    let ser = Zeroizing::new(bincode::serialize(msg).map_err(io::Error::other)?);
+    stream.write_all(&(ser.len() as u32).to_le_bytes())?;
+    stream.write_all(&ser)
+}
+
+pub(crate) fn recv<T: DeserializeOwned>(stream: &mut UnixStream) -> io::Result<T> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+
+    let len = u32::from_le_bytes(len);
+    if len > MAX_FRAME_SZ {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+
+    let mut ser = Zeroizing::new(vec![0; len as usize]);
+    stream.read_exact(&mut ser)?;
+    bincode::deserialize(&ser).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Who a rule lets in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Principal {
+    Uid(u32),
+    Gid(u32),
+}
+
+/// Lets a user or group get the root key of a volume, or of every volume with `*`, and
+/// optionally rotate it too.
+///
+/// Rules are written as `<volume>:uid=<uid>` or `<volume>:gid=<gid>`, followed by `:rotate` if
+/// they allow rotating. Group rules match the peer's primary group or any of its supplementary
+/// groups.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub volume: String,
+    pub principal: Principal,
+    pub rotate: bool,
+}
+
+impl Rule {
+    fn allows(&self, request: &Request, peer: &Peer) -> bool {
+        (self.volume == "*" || self.volume == request.volume())
+            && (self.rotate || !matches!(request, Request::Rotate { .. }))
+            && match self.principal {
+                Principal::Uid(uid) => uid == peer.uid,
+                Principal::Gid(gid) => peer.gids.contains(&gid),
+            }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.principal {
+            Principal::Uid(uid) => write!(f, "{}:uid={uid}", self.volume)?,
+            Principal::Gid(gid) => write!(f, "{}:gid={gid}", self.volume)?,
+        }
+        if self.rotate {
+            write!(f, ":rotate")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Volumes are metadir paths, which can contain colons themselves.
+        let invalid = || {
+            format!(
+                "invalid rule {s:?} (expected <volume>:uid=<uid> or <volume>:gid=<gid>, \
+                 optionally followed by :rotate)"
+            )
+        };
+        let (rule, rotate) = match s.strip_suffix(":rotate") {
+            Some(rule) => (rule, true),
+            None => (s, false),
+        };
+        let (volume, principal) = rule.rsplit_once(':').ok_or_else(invalid)?;
+        let principal = match principal.split_once('=') {
+            Some(("uid", uid)) => Principal::Uid(uid.parse().map_err(|_| invalid())?),
+            Some(("gid", gid)) => Principal::Gid(gid.parse().map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        };
+
+        if volume.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            volume: volume.into(),
+            principal,
+            rotate,
+        })
+    }
+}
+
+/// A key agent that keeps each volume's root record in memory and serves it over a Unix socket,
+/// with a thread for each connection.
+///
+/// Every connection is checked against the rules using the peer credentials the kernel vouches
+/// for, along with the peer's supplementary groups. Without any rules, only the agent's own user
+/// gets in.
+pub struct Agent {
+    listener: UnixListener,
+    state: Arc<State>,
+}
+
+/// What every connection's thread shares.
+struct State {
+    records: Mutex<HashMap<String, Zeroizing<Vec<u8>>>>,
+    rules: Vec<Rule>,
+}
+
+impl Agent {
+    /// Listens on a socket with the given permissions. A socket left behind by an agent that's no
+    /// longer running gets replaced.
+    pub fn bind(path: impl AsRef<Path>, mode: u32, mut rules: Vec<Rule>) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        let is_socket =
+            fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+        if is_socket {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("an agent is already listening on {}", path.display()),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+
+        if rules.is_empty() {
+            rules.push(Rule {
+                volume: "*".into(),
+                principal: Principal::Uid(unsafe { libc::getuid() }),
+                rotate: true,
+            });
+        }
+
+        Ok(Self {
+            listener,
+            state: Arc::new(State {
+                records: Mutex::new(HashMap::new()),
+                rules,
+            }),
+        })
+    }
+
+    /// Serves connections until accepting one fails.
+    pub fn serve(&self) -> io::Result<()> {
+        loop {
+            let (mut stream, _) = self.listener.accept()?;
+            let state = self.state.clone();
+            thread::spawn(move || {
+                if let Err(err) = state.handle(&mut stream) {
+                    warn!("agent: dropping connection: {err}");
+                }
+            });
+        }
+    }
+
+    /// Answers every request on a connection until the client hangs up.
+    pub fn handle(&self, stream: &mut UnixStream) -> io::Result<()> {
+        self.state.handle(stream)
+    }
+}
+
+impl State {
+    fn handle(&self, stream: &mut UnixStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let peer = Peer::of(stream)?;
+
+        loop {
+            let request: Request = match recv(stream) {
+                Ok(request) => request,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+
+            let (action, volume) = (request.action(), request.volume());
+            if !self.rules.iter().any(|rule| rule.allows(&request, &peer)) {
+                info!(
+                    "agent: denied {action} of {volume} to pid {}, uid {}, gids {:?}",
+                    peer.pid, peer.uid, peer.gids
+                );
+                send(
+                    stream,
+                    &Response::Error(format!("{action} of {volume} denied")),
+                )?;
+                continue;
+            }
+
+            let response = match request {
+                Request::Get { volume } => {
+                    debug!("agent: get {volume} for uid {}", peer.uid);
+                    Response::Record(self.records.lock().unwrap().get(&volume).cloned())
+                }
+                Request::Rotate { volume, record } => {
+                    debug!("agent: rotate {volume} for uid {}", peer.uid);
+                    self.records.lock().unwrap().insert(volume, record);
+                    Response::Rotated
+                }
+            };
+
+            send(stream, &response)?;
+        }
+    }
+}
+
+/// The process on the other end of a connection.
+struct Peer {
+    pid: libc::pid_t,
+    uid: libc::uid_t,
+    /// The primary group, followed by any supplementary groups.
+    gids: Vec<libc::gid_t>,
+}
+
+impl Peer {
+    fn of(stream: &UnixStream) -> io::Result<Self> {
+        let cred = peer_cred(stream)?;
+        let mut gids = vec![cred.gid];
+        gids.extend(supplementary_groups(cred.pid, cred.uid).unwrap_or_default());
+
+        Ok(Self {
+            pid: cred.pid,
+            uid: cred.uid,
+            gids,
+        })
+    }
+}
+
+/// The credentials of the process on the other end of a connection, as of when it connected.
+pub(crate) fn peer_cred(stream: &UnixStream) -> io::Result<libc::ucred> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut _,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(cred)
+}
+
+/// Reads a process's supplementary groups from `/proc`, since the kernel only vouches for the
+/// primary group along with the peer's credentials.
+///
+/// The groups are read after the peer connected, so they're only trusted if the process still has
+/// the same effective uid, which a reused pid almost never does. Otherwise only the primary group
+/// counts.
+fn supplementary_groups(pid: libc::pid_t, uid: libc::uid_t) -> Option<Vec<libc::gid_t>> {
+    let status = fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .map(|ids| {
+                ids.split_whitespace()
+                    .map(str::parse)
+                    .collect::<Result<Vec<_>, _>>()
+            })
+    };
+
+    // Uids are listed as real, effective, saved, and filesystem.
+    let uids = field("Uid:")?.ok()?;
+    if uids.get(1) != Some(&uid) {
+        return None;
+    }
+
+    field("Groups:")?.ok()
+}
@@ -0,0 +1,44 @@
+use anyhow::Result;
+use clap::Parser;
+use log::warn;
+use sdbtreefs::{
+    agent::{self, Agent, Rule},
+    utils,
+};
+
+#[derive(Parser)]
+struct Args {
+    /// The socket to listen on [default: $XDG_RUNTIME_DIR/sdbtreefs-agent.sock, or
+    /// /run/sdbtreefs-agent.sock]
+    #[clap(short, long)]
+    socket: Option<String>,
+
+    /// The socket's permissions, in octal
+    #[clap(long, default_value = "600", value_parser = parse_mode)]
+    mode: u32,
+
+    /// Let a user or group get a volume's key: <VOLUME>:uid=<UID> or <VOLUME>:gid=<GID>, where
+    /// VOLUME is the volume's metadir or * for every volume. Add :rotate to let them replace it
+    /// too, which mounting needs [default: only the agent's own user, with rotate]
+    #[clap(long = "allow", value_name = "RULE")]
+    rules: Vec<Rule>,
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8).map_err(|_| format!("invalid mode {mode:?}"))
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    pretty_env_logger::init();
+
+    // The agent only ever holds keys, so it always tries to keep them out of swap and core dumps.
+    if let Err(err) = utils::lock_memory() {
+        warn!("couldn't lock the agent's memory: {err}");
+    }
+
+    let socket = args.socket.map_or_else(agent::default_socket, Into::into);
+    Agent::bind(&socket, args.mode, args.rules)?.serve()?;
+    Ok(())
+}
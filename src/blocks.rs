@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, ops::Range};
+
+/// The blocks of a file that have keys, stored as disjoint, non-adjacent ranges.
+///
+/// Blocks outside the set are holes: they read back as zeros and have no key in the tree.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct BlockSet {
+    /// Maps the start of each range to its (exclusive) end.
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl BlockSet {
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn contains(&self, block: u64) -> bool {
+        self.ranges
+            .range(..=block)
+            .next_back()
+            .is_some_and(|(_, &end)| block < end)
+    }
+
+    /// Returns whether `block` has a key, along with the end of the run of blocks that share its
+    /// status.
+    pub fn run(&self, block: u64) -> (bool, u64) {
+        match self.ranges.range(..=block).next_back() {
+            Some((_, &end)) if block < end => (true, end),
+            _ => (
+                false,
+                self.ranges
+                    .range(block..)
+                    .next()
+                    .map_or(u64::MAX, |(&start, _)| start),
+            ),
+        }
+    }
+
+    pub fn insert(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let mut start = range.start;
+        let mut end = range.end;
+
+        // Absorb a range that overlaps or touches us from the left.
+        if let Some((&prev_start, &prev_end)) = self.ranges.range(..=start).next_back() {
+            if prev_end >= start {
+                start = prev_start;
+                end = end.max(prev_end);
+            }
+        }
+
+        // Absorb every range that starts inside or right after us.
+        let absorbed: Vec<_> = self
+            .ranges
+            .range(start..=end)
+            .map(|(&start, &end)| (start, end))
+            .collect();
+        for (next_start, next_end) in absorbed {
+            self.ranges.remove(&next_start);
+            end = end.max(next_end);
+        }
+
+        self.ranges.insert(start, end);
+    }
+
+    pub fn remove(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let overlapping: Vec<_> = self
+            .ranges
+            .range(..range.end)
+            .filter(|(_, &end)| end > range.start)
+            .map(|(&start, &end)| (start, end))
+            .collect();
+
+        for (start, end) in overlapping {
+            self.ranges.remove(&start);
+            if start < range.start {
+                self.ranges.insert(start, range.start);
+            }
+            if end > range.end {
+                self.ranges.insert(range.end, end);
+            }
+        }
+    }
+
+    pub fn ranges(&self) -> impl Iterator<Item = Range<u64>> + '_ {
+        self.ranges.iter().map(|(&start, &end)| start..end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(ranges: &[Range<u64>]) -> BlockSet {
+        let mut blocks = BlockSet::default();
+        for range in ranges {
+            blocks.insert(range.clone());
+        }
+        blocks
+    }
+
+    fn ranges(blocks: &BlockSet) -> Vec<(u64, u64)> {
+        blocks
+            .ranges()
+            .map(|range| (range.start, range.end))
+            .collect()
+    }
+
+    #[test]
+    fn insert_merges_overlapping_and_adjacent_ranges() {
+        assert_eq!(ranges(&set(&[0..4, 2..6])), [(0, 6)]);
+        assert_eq!(ranges(&set(&[0..4, 4..6])), [(0, 6)]);
+        assert_eq!(ranges(&set(&[4..6, 0..4])), [(0, 6)]);
+        assert_eq!(ranges(&set(&[0..2, 4..6, 8..10, 1..9])), [(0, 10)]);
+        assert_eq!(ranges(&set(&[0..2, 3..5])), [(0, 2), (3, 5)]);
+        assert_eq!(ranges(&set(&[2..8, 3..5])), [(2, 8)]);
+    }
+
+    #[test]
+    fn insert_ignores_empty_ranges() {
+        let mut blocks = BlockSet::default();
+        blocks.insert(3..3);
+        assert!(blocks.is_empty());
+        assert_eq!(ranges(&set(&[0..2, 5..5])), [(0, 2)]);
+    }
+
+    #[test]
+    fn remove_splits_and_trims_ranges() {
+        let mut blocks = BlockSet::default();
+        blocks.insert(0..10);
+        blocks.remove(3..5);
+        assert_eq!(ranges(&blocks), [(0, 3), (5, 10)]);
+
+        blocks.remove(8..20);
+        assert_eq!(ranges(&blocks), [(0, 3), (5, 8)]);
+
+        blocks.remove(0..1);
+        assert_eq!(ranges(&blocks), [(1, 3), (5, 8)]);
+
+        blocks.remove(2..6);
+        assert_eq!(ranges(&blocks), [(1, 2), (6, 8)]);
+
+        blocks.remove(0..u64::MAX);
+        assert!(blocks.is_empty());
+    }
+
+    #[test]
+    fn remove_leaves_holes_alone() {
+        let mut blocks = set(&[0..2, 6..8]);
+        blocks.remove(3..5);
+        blocks.remove(4..4);
+        assert_eq!(ranges(&blocks), [(0, 2), (6, 8)]);
+    }
+
+    #[test]
+    fn contains_only_blocks_in_ranges() {
+        let blocks = set(&[2..4, 6..7]);
+        for (block, keyed) in [
+            (0, false),
+            (2, true),
+            (3, true),
+            (4, false),
+            (6, true),
+            (7, false),
+        ] {
+            assert_eq!(blocks.contains(block), keyed, "block {block}");
+        }
+    }
+
+    #[test]
+    fn run_ends_where_the_status_changes() {
+        let blocks = set(&[2..4, 6..7]);
+        assert_eq!(blocks.run(0), (false, 2));
+        assert_eq!(blocks.run(2), (true, 4));
+        assert_eq!(blocks.run(3), (true, 4));
+        assert_eq!(blocks.run(4), (false, 6));
+        assert_eq!(blocks.run(6), (true, 7));
+        assert_eq!(blocks.run(7), (false, u64::MAX));
+        assert_eq!(BlockSet::default().run(5), (false, u64::MAX));
+    }
+}
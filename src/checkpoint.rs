@@ -0,0 +1,110 @@
+use log::{debug, warn};
+use std::{
+    ffi::CString,
+    fs, io,
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// When the filesystem checkpoints its state (the key tree, mappings, links, and allocator).
+///
+/// Policies are checked whenever the filesystem handles a request. Interval checkpoints are also
+/// driven by a timer, so an idle mount still checkpoints within an interval of its last
+/// mutation. State is always persisted on unmount regardless of the policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CheckpointPolicy {
+    /// Checkpoint once this much time has passed since the last checkpoint.
+    Interval(Duration),
+
+    /// Checkpoint after this many mutating operations. This has to be at least 1.
+    Operations(u64),
+
+    /// Only checkpoint on `fsync` and unmount.
+    #[default]
+    OnSync,
+}
+
+pub(crate) struct Checkpointer {
+    policy: CheckpointPolicy,
+    ops: u64,
+    last: Instant,
+    mount: Option<CString>,
+    ticking: bool,
+}
+
+impl Checkpointer {
+    pub fn new(policy: CheckpointPolicy) -> Self {
+        Self {
+            policy,
+            ops: 0,
+            last: Instant::now(),
+            mount: None,
+            ticking: false,
+        }
+    }
+
+    /// Sets the mount that the interval timer pokes once it starts.
+    ///
+    /// The path is made absolute, since libfuse changes to `/` when it forks into the background.
+    pub fn arm(&mut self, mount: &str) -> io::Result<()> {
+        let mount = fs::canonicalize(mount)?;
+        self.mount = CString::new(mount.as_os_str().as_bytes()).ok();
+        Ok(())
+    }
+
+    /// Records a request, returning whether a checkpoint is due.
+    pub fn record(&mut self, mutated: bool) -> bool {
+        if mutated {
+            self.ops += 1;
+        }
+
+        match self.policy {
+            CheckpointPolicy::Interval(interval) => {
+                self.start_timer(interval);
+                self.ops > 0 && self.last.elapsed() >= interval
+            }
+            CheckpointPolicy::Operations(ops) => self.ops >= ops,
+            CheckpointPolicy::OnSync => false,
+        }
+    }
+
+    /// Whether an `fsync` should trigger a checkpoint.
+    pub fn on_sync(&self) -> bool {
+        self.policy == CheckpointPolicy::OnSync && self.ops > 0
+    }
+
+    /// Resets the checkpointer after a checkpoint.
+    pub fn reset(&mut self) {
+        self.ops = 0;
+        self.last = Instant::now();
+    }
+
+    /// Starts a thread that sends the mount a `statfs` every interval, which gets handled like
+    /// any other request and checkpoints if one is due.
+    ///
+    /// The timer starts from the first request rather than at mount time, since libfuse forks
+    /// into the background after mounting and threads don't survive that.
+    fn start_timer(&mut self, interval: Duration) {
+        if self.ticking {
+            return;
+        }
+        let Some(mount) = self.mount.clone() else {
+            return;
+        };
+        self.ticking = true;
+
+        debug!("starting the checkpoint timer");
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+
+            let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+            if unsafe { libc::statvfs(mount.as_ptr(), stat.as_mut_ptr()) } < 0 {
+                let err = io::Error::last_os_error();
+                warn!("stopping the checkpoint timer, the mount can't be reached: {err}");
+                break;
+            }
+        });
+    }
+}
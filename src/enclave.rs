@@ -0,0 +1,493 @@
+mod agent;
+mod direct;
+mod keyring;
+mod wrap;
+
+pub use agent::AgentEnclave;
+pub use keyring::{Keyring, KeyringEnclave};
+pub use wrap::{KeyslotInfo, KEYSLOTS};
+
+use crate::{
+    error::{Error, Result},
+    Key,
+};
+use direct::DirectFile;
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::{fs::File, path::Path};
+use wrap::Wrapper;
+use zeroize::Zeroizing;
+
+/// The number of root record slots in a file enclave.
+pub(crate) const SLOTS: u64 = 2;
+
+/// Somewhere to keep the root record, which is the only secret the filesystem needs to load.
+///
+/// Everything else is encrypted under or authenticated by the root key, so an enclave only has to
+/// keep a single record safe, and destroy old ones once they're replaced.
+pub trait Enclave<const KEY_SZ: usize> {
+    /// Whether the enclave holds a root record, meaning there's a volume to load.
+    fn is_initialized(&mut self) -> Result<bool>;
+
+    /// Returns the committed root record, destroying any older record that an interrupted
+    /// `replace` left behind.
+    fn get(&mut self) -> Result<Option<Root<KEY_SZ>>>;
+
+    /// Returns the committed root record without writing anything, for reading it while the
+    /// volume isn't being loaded. Enclaves whose `get` writes have to override this.
+    fn peek(&mut self) -> Result<Option<Root<KEY_SZ>>> {
+        self.get()
+    }
+
+    /// Atomically replaces the root record. Once this returns, the new record is durable and the
+    /// old one is gone.
+    fn replace(&mut self, root: &Root<KEY_SZ>) -> Result<()>;
+
+    /// The underlying file of enclaves that can still hold the layout from before the superblock,
+    /// which kept the root key raw at the start of the file.
+    fn legacy_file(&mut self) -> Option<&mut File> {
+        None
+    }
+
+    /// Locks any key material the enclave holds in memory again after a fork.
+    fn relock(&self) {}
+}
+
+impl<const KEY_SZ: usize, E: Enclave<KEY_SZ> + ?Sized> Enclave<KEY_SZ> for Box<E> {
+    fn is_initialized(&mut self) -> Result<bool> {
+        (**self).is_initialized()
+    }
+
+    fn get(&mut self) -> Result<Option<Root<KEY_SZ>>> {
+        (**self).get()
+    }
+
+    fn peek(&mut self) -> Result<Option<Root<KEY_SZ>>> {
+        (**self).peek()
+    }
+
+    fn replace(&mut self, root: &Root<KEY_SZ>) -> Result<()> {
+        (**self).replace(root)
+    }
+
+    fn legacy_file(&mut self) -> Option<&mut File> {
+        (**self).legacy_file()
+    }
+
+    fn relock(&self) {
+        (**self).relock()
+    }
+}
+
+/// The root key of the committed epoch.
+///
+/// Records are encoded as `[epoch][root key][checksum]`, so torn or corrupt ones can be told
+/// apart from real ones. The root key is wiped when the record is dropped.
+#[derive(Clone)]
+pub struct Root<const KEY_SZ: usize> {
+    pub epoch: u64,
+    pub root_key: Zeroizing<Key<KEY_SZ>>,
+}
+
+impl<const KEY_SZ: usize> Root<KEY_SZ> {
+    pub const SIZE: usize = 8 + KEY_SZ + 32;
+
+    /// The slot index that a given epoch is stored in. Manifests alternate the same way.
+    pub(crate) fn index(epoch: u64) -> u64 {
+        epoch % SLOTS
+    }
+
+    pub fn encode(&self) -> Zeroizing<Vec<u8>> {
+        let mut raw = Zeroizing::new(Vec::with_capacity(Self::SIZE));
+        raw.extend_from_slice(&self.epoch.to_le_bytes());
+        raw.extend_from_slice(self.root_key.as_slice());
+        let checksum = Self::checksum(&raw);
+        raw.extend_from_slice(&checksum);
+        raw
+    }
+
+    /// Decodes a record, returning `None` if it's empty, torn, or otherwise corrupt.
+    pub fn decode(raw: &[u8]) -> Option<Self> {
+        if raw.len() != Self::SIZE {
+            return None;
+        }
+
+        let (record, checksum) = raw.split_at(8 + KEY_SZ);
+        if Self::checksum(record) != checksum {
+            return None;
+        }
+
+        Some(Self {
+            epoch: u64::from_le_bytes(record[..8].try_into().unwrap()),
+            root_key: Zeroizing::new(record[8..].try_into().unwrap()),
+        })
+    }
+
+    fn checksum(record: &[u8]) -> [u8; 32] {
+        Sha256::digest(record).into()
+    }
+}
+
+/// An enclave kept in a file or at the start of a block device, optionally wrapped under one or
+/// more passphrases or keyfiles.
+///
+/// Consecutive epochs alternate between two slots, so a torn write only ever damages the slot
+/// being committed and the previous record survives in the other slot until the new one is
+/// durable.
+///
+/// Records are overwritten in place at fixed offsets in a preallocated file, with direct I/O where
+/// the filesystem supports it, and synced before `replace` returns. That only destroys the old
+/// record if the filesystem overwrites in place too, so enclaves on copy-on-write filesystems are
+/// refused unless they're explicitly allowed.
+pub struct FileEnclave {
+    file: DirectFile,
+    wrapper: Option<Wrapper>,
+}
+
+impl FileEnclave {
+    /// Opens a plain enclave file, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::options().open(path)
+    }
+
+    /// Opens an enclave file wrapped under a passphrase or keyfile. A new enclave gets wrapped
+    /// under it, an existing one has to have a keyslot for it.
+    pub fn open_with_passphrase(path: impl AsRef<Path>, passphrase: &[u8]) -> Result<Self> {
+        Self::options().passphrase(passphrase).open(path)
+    }
+
+    pub fn options() -> FileEnclaveOptions {
+        FileEnclaveOptions::default()
+    }
+
+    /// Whether there's no enclave at `path` yet, so opening it would create one.
+    pub fn is_new(path: impl AsRef<Path>) -> Result<bool> {
+        direct::is_blank(path.as_ref())
+    }
+
+    /// Adds a keyslot for another passphrase or keyfile, returning its index.
+    pub fn add_keyslot(&mut self, passphrase: &[u8]) -> Result<usize> {
+        let wrapper = self.wrapper.as_mut().ok_or(Error::NotWrapped)?;
+        let index = wrapper.add(passphrase, &mut OsRng)?;
+
+        self.file
+            .write_at(Wrapper::keyslot_offset(index), &wrapper.keyslot(index))?;
+        Ok(index)
+    }
+
+    /// Removes a keyslot, overwriting it with random bytes and then zeros.
+    ///
+    /// This only overwrites the keyslot in place, so copies that the filesystem or the device
+    /// kept elsewhere, like on copy-on-write filesystems or SSDs, can survive it.
+    pub fn remove_keyslot(&mut self, index: usize) -> Result<()> {
+        let wrapper = self.wrapper.as_mut().ok_or(Error::NotWrapped)?;
+        wrapper.remove(index)?;
+
+        let mut noise = wrapper.keyslot(index);
+        OsRng.fill_bytes(&mut noise);
+        for raw in [noise, wrapper.keyslot(index)] {
+            self.file.write_at(Wrapper::keyslot_offset(index), &raw)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn keyslots(&self) -> Result<Vec<KeyslotInfo>> {
+        Ok(self.wrapper.as_ref().ok_or(Error::NotWrapped)?.keyslots())
+    }
+
+    /// The size of a slot, which is larger if it's wrapped.
+    fn slot_size<const KEY_SZ: usize>(&self) -> usize {
+        match self.wrapper {
+            Some(_) => Root::<KEY_SZ>::SIZE + Wrapper::OVERHEAD,
+            None => Root::<KEY_SZ>::SIZE,
+        }
+    }
+
+    /// Where a slot starts. Slots follow the header in wrapped enclaves.
+    fn slot_offset<const KEY_SZ: usize>(&self, index: u64) -> u64 {
+        let start = match self.wrapper {
+            Some(_) => Wrapper::HEADER_SIZE,
+            None => 0,
+        };
+        (start + index as usize * self.slot_size::<KEY_SZ>()) as u64
+    }
+
+    /// Reads every slot, with `None` for slots that are empty or corrupt.
+    fn read_slots<const KEY_SZ: usize>(&mut self) -> Result<Vec<Option<Root<KEY_SZ>>>> {
+        // Slots past the end of the file simply haven't been written yet.
+        let raw = self.read_slots_raw::<KEY_SZ>()?;
+
+        Ok(raw
+            .chunks_exact(self.slot_size::<KEY_SZ>())
+            .zip(0..)
+            .map(|(raw, index)| match &self.wrapper {
+                Some(wrapper) => wrapper
+                    .unseal(index, raw)
+                    .and_then(|raw| Root::decode(&raw)),
+                None => Root::decode(raw),
+            })
+            .collect())
+    }
+
+    fn check_device_size<const KEY_SZ: usize>(&self) -> Result<()> {
+        self.file.check_capacity(self.slot_offset::<KEY_SZ>(SLOTS))
+    }
+
+    fn read_slots_raw<const KEY_SZ: usize>(&mut self) -> Result<Zeroizing<Vec<u8>>> {
+        self.file.read_at(
+            self.slot_offset::<KEY_SZ>(0),
+            SLOTS as usize * self.slot_size::<KEY_SZ>(),
+        )
+    }
+
+    fn write_slot<const KEY_SZ: usize>(&mut self, root: &Root<KEY_SZ>) -> Result<()> {
+        let index = Root::<KEY_SZ>::index(root.epoch);
+        let raw = match &self.wrapper {
+            Some(wrapper) => Zeroizing::new(wrapper.seal(index, &root.encode(), &mut OsRng)),
+            None => root.encode(),
+        };
+
+        self.file.write_at(self.slot_offset::<KEY_SZ>(index), &raw)
+    }
+
+    fn wipe_slot<const KEY_SZ: usize>(&mut self, index: u64) -> Result<()> {
+        self.file.write_at(
+            self.slot_offset::<KEY_SZ>(index),
+            &vec![0; self.slot_size::<KEY_SZ>()],
+        )
+    }
+}
+
+impl<const KEY_SZ: usize> Enclave<KEY_SZ> for FileEnclave {
+    fn is_initialized(&mut self) -> Result<bool> {
+        // This is the first thing asked of an enclave, so it's where devices that can't fit the
+        // layout get refused.
+        self.check_device_size::<KEY_SZ>()?;
+
+        // New enclaves are preallocated, so it's what's in the slots that counts, not the length.
+        if self.read_slots_raw::<KEY_SZ>()?.iter().all(|&b| b == 0) {
+            return Ok(false);
+        }
+
+        // Devices aren't zeroed for us, so whatever was on one before can't pass for a record.
+        if self.file.is_device() && self.read_slots::<KEY_SZ>()?.iter().all(Option::is_none) {
+            return Err(Error::DeviceNotZeroed);
+        }
+        Ok(true)
+    }
+
+    fn get(&mut self) -> Result<Option<Root<KEY_SZ>>> {
+        let Some(root) = Enclave::<KEY_SZ>::peek(self)? else {
+            return Ok(None);
+        };
+
+        // The other slot is either the previous epoch or a commit that was torn before it became
+        // durable, and either way it's garbage now.
+        self.wipe_slot::<KEY_SZ>(Root::<KEY_SZ>::index(root.epoch + 1))?;
+
+        Ok(Some(root))
+    }
+
+    fn peek(&mut self) -> Result<Option<Root<KEY_SZ>>> {
+        Ok(self
+            .read_slots::<KEY_SZ>()?
+            .into_iter()
+            .flatten()
+            .max_by_key(|root| root.epoch))
+    }
+
+    fn replace(&mut self, root: &Root<KEY_SZ>) -> Result<()> {
+        // Writing the new slot is the commit point, the old one only goes once it's durable.
+        self.write_slot(root)?;
+        self.wipe_slot::<KEY_SZ>(Root::<KEY_SZ>::index(root.epoch + 1))
+    }
+
+    fn legacy_file(&mut self) -> Option<&mut File> {
+        match self.wrapper {
+            Some(_) => None,
+            None => Some(self.file.buffered()),
+        }
+    }
+
+    fn relock(&self) {
+        if let Some(wrapper) = &self.wrapper {
+            wrapper.relock();
+        }
+    }
+}
+
+/// Options for opening a [`FileEnclave`].
+#[derive(Default)]
+pub struct FileEnclaveOptions {
+    passphrase: Option<Zeroizing<Vec<u8>>>,
+    allow_insecure: bool,
+}
+
+impl FileEnclaveOptions {
+    /// Wraps the enclave under a passphrase or keyfile.
+    pub fn passphrase(mut self, passphrase: &[u8]) -> Self {
+        self.passphrase = Some(Zeroizing::new(passphrase.into()));
+        self
+    }
+
+    /// Keeps the enclave on a filesystem that can't overwrite it in place, with just a warning.
+    pub fn allow_insecure(mut self, allow_insecure: bool) -> Self {
+        self.allow_insecure = allow_insecure;
+        self
+    }
+
+    /// Opens the enclave file, creating it if it doesn't exist.
+    pub fn open(self, path: impl AsRef<Path>) -> Result<FileEnclave> {
+        let path = path.as_ref();
+        direct::check_overwritable(path, self.allow_insecure)?;
+
+        let mut file = DirectFile::open(path)?;
+        let wrapper = wrap::open_wrapper(
+            &mut file,
+            self.passphrase
+                .as_ref()
+                .map(|passphrase| passphrase.as_slice()),
+            &mut OsRng,
+        )?;
+        Ok(FileEnclave { file, wrapper })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, path::PathBuf, process};
+
+    const KEY_SZ: usize = 32;
+
+    fn root(epoch: u64) -> Root<KEY_SZ> {
+        Root {
+            epoch,
+            root_key: Zeroizing::new([epoch as u8; KEY_SZ]),
+        }
+    }
+
+    /// A fresh path for a test's enclave, which is removed when it's dropped.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("sdbtreefs-{name}-{}", process::id()));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn slots(enclave: &mut FileEnclave) -> Vec<Option<u64>> {
+        enclave
+            .read_slots::<KEY_SZ>()
+            .unwrap()
+            .into_iter()
+            .map(|root| root.map(|root| root.epoch))
+            .collect()
+    }
+
+    #[test]
+    fn root_round_trips() {
+        let raw = root(7).encode();
+        assert_eq!(raw.len(), Root::<KEY_SZ>::SIZE);
+
+        let decoded = Root::<KEY_SZ>::decode(&raw).unwrap();
+        assert_eq!(decoded.epoch, 7);
+        assert_eq!(*decoded.root_key, [7; KEY_SZ]);
+    }
+
+    #[test]
+    fn root_rejects_corrupt_records() {
+        let raw = root(7).encode();
+        for i in [0, 8, Root::<KEY_SZ>::SIZE - 1] {
+            let mut corrupt = raw.clone();
+            corrupt[i] ^= 1;
+            assert!(Root::<KEY_SZ>::decode(&corrupt).is_none());
+        }
+        assert!(Root::<KEY_SZ>::decode(&raw[1..]).is_none());
+        assert!(Root::<KEY_SZ>::decode(&[0; Root::<KEY_SZ>::SIZE]).is_none());
+    }
+
+    #[test]
+    fn file_enclave_alternates_slots() {
+        let path = TempPath::new("alternate");
+        let mut enclave = FileEnclave::open(&path.0).unwrap();
+        assert!(!Enclave::<KEY_SZ>::is_initialized(&mut enclave).unwrap());
+        assert!(Enclave::<KEY_SZ>::get(&mut enclave).unwrap().is_none());
+
+        enclave.replace(&root(1)).unwrap();
+        assert!(Enclave::<KEY_SZ>::is_initialized(&mut enclave).unwrap());
+        assert_eq!(slots(&mut enclave), [None, Some(1)]);
+
+        enclave.replace(&root(2)).unwrap();
+        assert_eq!(slots(&mut enclave), [Some(2), None]);
+
+        enclave.replace(&root(3)).unwrap();
+        assert_eq!(slots(&mut enclave), [None, Some(3)]);
+
+        // The record survives reopening the enclave.
+        drop(enclave);
+        let mut enclave = FileEnclave::open(&path.0).unwrap();
+        let got: Root<KEY_SZ> = enclave.get().unwrap().unwrap();
+        assert_eq!(got.epoch, 3);
+        assert_eq!(*got.root_key, [3; KEY_SZ]);
+    }
+
+    #[test]
+    fn file_enclave_recovers_from_an_interrupted_replace() {
+        let path = TempPath::new("interrupted");
+        let mut enclave = FileEnclave::open(&path.0).unwrap();
+        enclave.replace(&root(1)).unwrap();
+
+        // A replace that made its new slot durable, but didn't get to wipe the old one.
+        enclave.write_slot(&root(2)).unwrap();
+        assert_eq!(slots(&mut enclave), [Some(2), Some(1)]);
+
+        // Peeking leaves both slots alone, getting destroys the old one.
+        let peeked: Root<KEY_SZ> = enclave.peek().unwrap().unwrap();
+        assert_eq!(peeked.epoch, 2);
+        assert_eq!(slots(&mut enclave), [Some(2), Some(1)]);
+
+        let got: Root<KEY_SZ> = enclave.get().unwrap().unwrap();
+        assert_eq!(got.epoch, 2);
+        assert_eq!(slots(&mut enclave), [Some(2), None]);
+    }
+
+    #[test]
+    fn file_enclave_falls_back_from_a_torn_replace() {
+        let path = TempPath::new("torn");
+        let mut enclave = FileEnclave::open(&path.0).unwrap();
+        enclave.replace(&root(1)).unwrap();
+
+        // A replace that was torn before its new slot became durable.
+        let mut torn = root(2).encode();
+        torn[Root::<KEY_SZ>::SIZE / 2] ^= 1;
+        let offset = enclave.slot_offset::<KEY_SZ>(Root::<KEY_SZ>::index(2));
+        enclave.file.write_at(offset, &torn).unwrap();
+
+        let got: Root<KEY_SZ> = enclave.get().unwrap().unwrap();
+        assert_eq!(got.epoch, 1);
+        assert_eq!(slots(&mut enclave), [None, Some(1)]);
+    }
+
+    #[test]
+    fn file_enclave_is_exclusive() {
+        let path = TempPath::new("exclusive");
+        let enclave = FileEnclave::open(&path.0).unwrap();
+        assert!(matches!(
+            FileEnclave::open(&path.0),
+            Err(Error::EnclaveInUse)
+        ));
+
+        drop(enclave);
+        assert!(FileEnclave::open(&path.0).is_ok());
+    }
+}
@@ -0,0 +1,177 @@
+use super::{Enclave, Root};
+use crate::{
+    agent::{self, Request, Response, REQUEST_TIMEOUT},
+    error::{Error, Result},
+};
+use std::{
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+/// An enclave kept by a key agent listening on a Unix socket.
+///
+/// The agent is trusted with the record and with replacing it atomically, so it has to be running
+/// as root or as a user we trust, our own by default. Each call makes a fresh connection, so the
+/// agent can be restarted between calls.
+pub struct AgentEnclave {
+    socket: PathBuf,
+    volume: String,
+    agent_uid: libc::uid_t,
+}
+
+impl AgentEnclave {
+    pub fn new(socket: impl AsRef<Path>, volume: impl Into<String>) -> Self {
+        Self {
+            socket: socket.as_ref().into(),
+            volume: volume.into(),
+            agent_uid: unsafe { libc::getuid() },
+        }
+    }
+
+    /// Trusts an agent running as `uid`, instead of one running as our own user. Root is always
+    /// trusted.
+    pub fn agent_uid(mut self, uid: libc::uid_t) -> Self {
+        self.agent_uid = uid;
+        self
+    }
+
+    fn call(&self, request: &Request) -> Result<Response> {
+        let mut stream = UnixStream::connect(&self.socket)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+        // Anyone who can put a socket where we look could otherwise hand us a root key, or be
+        // handed ours.
+        let uid = agent::peer_cred(&stream)?.uid;
+        if uid != 0 && uid != self.agent_uid {
+            return Err(Error::Agent(format!(
+                "{} is served by uid {uid}, which isn't trusted",
+                self.socket.display()
+            )));
+        }
+
+        agent::send(&mut stream, request)?;
+        match agent::recv(&mut stream)? {
+            Response::Error(err) => Err(Error::Agent(err)),
+            response => Ok(response),
+        }
+    }
+}
+
+impl<const KEY_SZ: usize> Enclave<KEY_SZ> for AgentEnclave {
+    fn is_initialized(&mut self) -> Result<bool> {
+        Ok(Enclave::<KEY_SZ>::get(self)?.is_some())
+    }
+
+    fn get(&mut self) -> Result<Option<Root<KEY_SZ>>> {
+        let request = Request::Get {
+            volume: self.volume.clone(),
+        };
+        match self.call(&request)? {
+            Response::Record(Some(record)) => {
+                Ok(Some(Root::decode(&record).ok_or(Error::Enclave)?))
+            }
+            Response::Record(None) => Ok(None),
+            _ => Err(Error::Agent("unexpected response".into())),
+        }
+    }
+
+    fn replace(&mut self, root: &Root<KEY_SZ>) -> Result<()> {
+        let request = Request::Rotate {
+            volume: self.volume.clone(),
+            record: root.encode(),
+        };
+        match self.call(&request)? {
+            Response::Rotated => Ok(()),
+            _ => Err(Error::Agent("unexpected response".into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, Principal, Rule};
+    use std::{env, fs, process, thread};
+    use zeroize::Zeroizing;
+
+    const KEY_SZ: usize = 32;
+
+    fn root(epoch: u64) -> Root<KEY_SZ> {
+        Root {
+            epoch,
+            root_key: Zeroizing::new([epoch as u8; KEY_SZ]),
+        }
+    }
+
+    /// Starts an agent on a fresh socket, returning the socket's path.
+    fn spawn_agent(name: &str, rules: Vec<Rule>) -> PathBuf {
+        let socket = env::temp_dir().join(format!("sdbtreefs-agent-{name}-{}", process::id()));
+        let _ = fs::remove_file(&socket);
+
+        let agent = Agent::bind(&socket, 0o600, rules).unwrap();
+        thread::spawn(move || agent.serve());
+        socket
+    }
+
+    fn epoch(enclave: &mut AgentEnclave) -> Option<u64> {
+        Enclave::<KEY_SZ>::get(enclave)
+            .unwrap()
+            .map(|root| root.epoch)
+    }
+
+    #[test]
+    fn gets_and_replaces_records_through_an_agent() {
+        let socket = spawn_agent("replace", vec![]);
+        let mut enclave = AgentEnclave::new(&socket, "/volume");
+        assert!(!Enclave::<KEY_SZ>::is_initialized(&mut enclave).unwrap());
+        assert_eq!(epoch(&mut enclave), None);
+
+        enclave.replace(&root(1)).unwrap();
+        assert!(Enclave::<KEY_SZ>::is_initialized(&mut enclave).unwrap());
+        assert_eq!(epoch(&mut enclave), Some(1));
+
+        enclave.replace(&root(2)).unwrap();
+        let got: Root<KEY_SZ> = enclave.get().unwrap().unwrap();
+        assert_eq!(got.epoch, 2);
+        assert_eq!(*got.root_key, [2; KEY_SZ]);
+
+        // Volumes are kept apart.
+        let mut other = AgentEnclave::new(&socket, "/other");
+        assert_eq!(epoch(&mut other), None);
+
+        let _ = fs::remove_file(&socket);
+    }
+
+    #[test]
+    fn agent_enforces_its_rules() {
+        let uid = unsafe { libc::getuid() };
+        let socket = spawn_agent(
+            "rules",
+            vec![
+                Rule {
+                    volume: "/readable".into(),
+                    principal: Principal::Uid(uid),
+                    rotate: false,
+                },
+                Rule {
+                    volume: "*".into(),
+                    principal: Principal::Uid(uid.wrapping_add(1)),
+                    rotate: true,
+                },
+            ],
+        );
+
+        let mut readable = AgentEnclave::new(&socket, "/readable");
+        assert_eq!(epoch(&mut readable), None);
+        assert!(matches!(readable.replace(&root(1)), Err(Error::Agent(_))));
+
+        let mut hidden = AgentEnclave::new(&socket, "/hidden");
+        assert!(matches!(
+            Enclave::<KEY_SZ>::get(&mut hidden),
+            Err(Error::Agent(_))
+        ));
+
+        let _ = fs::remove_file(&socket);
+    }
+}
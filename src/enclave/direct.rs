@@ -0,0 +1,327 @@
+use crate::error::{Error, Result};
+use log::warn;
+use std::{
+    ffi::CString,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    mem::MaybeUninit,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileExt, FileTypeExt, OpenOptionsExt},
+        io::AsRawFd,
+    },
+    path::Path,
+};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Direct I/O has to be aligned to the logical block size, which this covers on every device we
+/// care about.
+const ALIGN: usize = 4096;
+
+/// The size new enclaves are preallocated to, and the size of the area reserved at the start of
+/// a device. Records and headers always fit in it, so they're only ever overwritten in place.
+const RESERVED_SZ: usize = ALIGN;
+
+/// Filesystems that never overwrite data in place, so a replaced record survives in freed blocks
+/// no matter how we write it.
+const COPY_ON_WRITE: [(u32, &str); 5] = [
+    (0x9123683e, "btrfs"),
+    (0x2fc12fc1, "zfs"),
+    (0xca451a4e, "bcachefs"),
+    (0x3434, "nilfs2"),
+    (0xf2f52010, "f2fs"),
+];
+
+/// An enclave file that's written in place, bypassing the page cache where possible, and synced
+/// after every write.
+///
+/// Every write is a read-modify-write of the aligned blocks it covers, so the same physical
+/// blocks get overwritten on filesystems that write in place. The enclave can also be a block
+/// device, in which case it takes up the reserved area at the start of the device and there's no
+/// filesystem in the way at all.
+pub(crate) struct DirectFile {
+    file: File,
+    direct: bool,
+    device_size: Option<u64>,
+}
+
+impl DirectFile {
+    /// Opens an enclave file, creating and preallocating it if it doesn't exist, or an enclave
+    /// device.
+    ///
+    /// Enclaves are opened exclusively for as long as they're open, since updates rewrite whole
+    /// blocks that can hold more than what they change. Opening one that's already open, say by a
+    /// mount, fails with [`Error::EnclaveInUse`].
+    pub fn open(path: &Path) -> Result<Self> {
+        if is_block_device(path) {
+            return Self::open_device(path);
+        }
+
+        let open = |flags| {
+            File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .custom_flags(flags)
+                .open(path)
+        };
+
+        // Not every filesystem supports direct I/O, tmpfs being the usual one.
+        let (file, direct) = match open(libc::O_DIRECT) {
+            Ok(file) => (file, true),
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+                warn!(
+                    "{}: direct I/O isn't supported, the enclave goes through the page cache",
+                    path.display()
+                );
+                (open(0)?, false)
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        // The lock goes with the open file, so it's kept across libfuse forking into the
+        // background.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+            let err = io::Error::last_os_error();
+            return Err(match err.kind() {
+                io::ErrorKind::WouldBlock => Error::EnclaveInUse,
+                _ => err.into(),
+            });
+        }
+
+        let mut file = Self {
+            file,
+            direct,
+            device_size: None,
+        };
+        if file.file.metadata()?.len() == 0 {
+            file.preallocate(path)?;
+        }
+
+        Ok(file)
+    }
+
+    /// Opens a block device with every write going straight to the device.
+    ///
+    /// Devices aren't formatted for us, so a new one has to be zeroed before its first use.
+    /// They're opened exclusively, which fails if the device is mounted or otherwise in use.
+    fn open_device(path: &Path) -> Result<Self> {
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT | libc::O_SYNC | libc::O_EXCL)
+            .open(path)
+            .map_err(|err| match err.raw_os_error() {
+                Some(libc::EBUSY) => Error::EnclaveInUse,
+                _ => err.into(),
+            })?;
+
+        // The metadata of a device node doesn't know the device's size, but seeking to its end
+        // does.
+        let device_size = file.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            file,
+            direct: true,
+            device_size: Some(device_size),
+        })
+    }
+
+    pub fn is_device(&self) -> bool {
+        self.device_size.is_some()
+    }
+
+    /// Makes sure a device has room for `len` bytes of records, which are written in whole
+    /// aligned blocks. Files just grow.
+    pub fn check_capacity(&self, len: u64) -> Result<()> {
+        let needed = align_up(len as usize) as u64;
+        match self.device_size {
+            Some(size) if size < needed => Err(Error::EnclaveTooSmall { size, needed }),
+            _ => Ok(()),
+        }
+    }
+
+    fn preallocate(&mut self, path: &Path) -> Result<()> {
+        let ret = unsafe { libc::posix_fallocate(self.file.as_raw_fd(), 0, RESERVED_SZ as _) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret).into());
+        }
+        self.file.sync_all()?;
+
+        // The file itself has to be durable too, not just its contents.
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            File::open(parent)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Reads the whole file, or the reserved area of a device.
+    pub fn read_all(&mut self) -> Result<Zeroizing<Vec<u8>>> {
+        let len = match self.device_size {
+            Some(device_size) => device_size.min(RESERVED_SZ as u64),
+            None => self.file.metadata()?.len(),
+        };
+        self.read_at(0, len as usize)
+    }
+
+    /// Reads `len` bytes at `offset`, reading anything past the end of the file as zeros.
+    pub fn read_at(&mut self, offset: u64, len: usize) -> Result<Zeroizing<Vec<u8>>> {
+        let start = align_down(offset as usize);
+        let end = align_up(offset as usize + len);
+
+        let mut buf = AlignedBuf::new(end - start);
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self
+                .file
+                .read_at(&mut buf.as_mut()[filled..], (start + filled) as u64)?
+            {
+                0 => break,
+                n => filled += n,
+            }
+        }
+
+        let skip = offset as usize - start;
+        Ok(Zeroizing::new(buf.as_ref()[skip..skip + len].to_vec()))
+    }
+
+    /// Overwrites `data` at `offset` in place and syncs it.
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let start = align_down(offset as usize);
+        let end = align_up(offset as usize + data.len());
+
+        let mut buf = AlignedBuf::new(end - start);
+        buf.as_mut()
+            .copy_from_slice(&self.read_at(start as u64, end - start)?);
+
+        let skip = offset as usize - start;
+        buf.as_mut()[skip..skip + data.len()].copy_from_slice(data);
+
+        self.file.write_all_at(buf.as_ref(), start as u64)?;
+        Ok(self.file.sync_all()?)
+    }
+
+    /// The underlying file, switched back to buffered I/O so it can be read and written at any
+    /// alignment.
+    pub fn buffered(&mut self) -> &mut File {
+        if self.direct {
+            let fd = self.file.as_raw_fd();
+            unsafe {
+                let flags = libc::fcntl(fd, libc::F_GETFL);
+                libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT);
+            }
+            self.direct = false;
+        }
+        &mut self.file
+    }
+}
+
+/// Whether an enclave file or device is missing or still all zeros.
+pub(crate) fn is_blank(path: &Path) -> Result<bool> {
+    let mut raw = Zeroizing::new(Vec::with_capacity(RESERVED_SZ));
+    match File::open(path) {
+        Ok(file) => file.take(RESERVED_SZ as u64).read_to_end(&mut raw)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(raw.iter().all(|&b| b == 0))
+}
+
+fn is_block_device(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_block_device())
+}
+
+/// Refuses to keep an enclave on a copy-on-write filesystem, where overwriting a record doesn't
+/// destroy it, unless that's explicitly allowed.
+pub(crate) fn check_overwritable(path: &Path, allow_insecure: bool) -> Result<()> {
+    // Devices are written directly, whatever filesystem their node lives on.
+    if is_block_device(path) {
+        return Ok(());
+    }
+
+    // The enclave may not exist yet, in which case its directory is what matters.
+    let target = match path.exists() {
+        true => path,
+        false => match path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            Some(parent) => parent,
+            None => Path::new("."),
+        },
+    };
+    let target = CString::new(target.as_os_str().as_bytes()).map_err(|_| Error::Enclave)?;
+
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(target.as_ptr(), stat.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let fs_type = unsafe { stat.assume_init() }.f_type as u32;
+
+    let Some((_, name)) = COPY_ON_WRITE.iter().find(|(magic, _)| *magic == fs_type) else {
+        return Ok(());
+    };
+
+    let reason = format!(
+        "{} is on {name}, which is copy-on-write, so replaced root keys (and with them every \
+         deleted key) can survive in freed blocks; keep the enclave on a filesystem that \
+         overwrites in place, like ext4 or xfs, or on a raw device",
+        path.display()
+    );
+    if !allow_insecure {
+        return Err(Error::InsecureEnclave(reason));
+    }
+
+    warn!("{reason}");
+    Ok(())
+}
+
+fn align_down(n: usize) -> usize {
+    n / ALIGN * ALIGN
+}
+
+fn align_up(n: usize) -> usize {
+    n.div_ceil(ALIGN) * ALIGN
+}
+
+/// A zeroed buffer whose start is aligned for direct I/O. It holds records, so it's wiped when
+/// it's dropped.
+struct AlignedBuf {
+    raw: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        let raw = vec![0; len + ALIGN];
+        let start = raw.as_ptr().align_offset(ALIGN);
+        Self { raw, start, len }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        self.raw.zeroize();
+    }
+}
+
+impl AsRef<[u8]> for AlignedBuf {
+    fn as_ref(&self) -> &[u8] {
+        &self.raw[self.start..self.start + self.len]
+    }
+}
+
+impl AsMut<[u8]> for AlignedBuf {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.raw[self.start..self.start + self.len]
+    }
+}
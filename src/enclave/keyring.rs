@@ -0,0 +1,201 @@
+use super::{Enclave, Root};
+use crate::error::{Error, Result};
+use std::{ffi::CString, fmt, io, str::FromStr};
+use zeroize::Zeroizing;
+
+const KEYCTL_UPDATE: libc::c_long = 2;
+const KEYCTL_SEARCH: libc::c_long = 10;
+const KEYCTL_READ: libc::c_long = 11;
+const KEY_TYPE: &[u8] = b"user\0";
+
+/// The keyring a [`KeyringEnclave`] keeps its key in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Keyring {
+    Thread,
+    Process,
+    Session,
+    #[default]
+    User,
+    UserSession,
+}
+
+impl Keyring {
+    /// The special ID the kernel resolves to this keyring for the calling thread.
+    fn spec(self) -> libc::c_long {
+        match self {
+            Self::Thread => -1,
+            Self::Process => -2,
+            Self::Session => -3,
+            Self::User => -4,
+            Self::UserSession => -5,
+        }
+    }
+}
+
+impl fmt::Display for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Thread => "thread",
+            Self::Process => "process",
+            Self::Session => "session",
+            Self::User => "user",
+            Self::UserSession => "user-session",
+        })
+    }
+}
+
+impl FromStr for Keyring {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "thread" => Ok(Self::Thread),
+            "process" => Ok(Self::Process),
+            "session" => Ok(Self::Session),
+            "user" => Ok(Self::User),
+            "user-session" => Ok(Self::UserSession),
+            _ => Err(format!(
+                "unknown keyring {s:?} (expected thread, process, session, user, or user-session)"
+            )),
+        }
+    }
+}
+
+/// An enclave kept as a `user` key in the Linux kernel keyring, so the root record never touches
+/// disk.
+///
+/// Once the key exists, `KEYCTL_UPDATE` swaps its payload atomically, which is all `replace`
+/// needs. The record only lives as long as the keyring it's in.
+pub struct KeyringEnclave {
+    description: CString,
+    keyring: Keyring,
+}
+
+impl KeyringEnclave {
+    /// Uses the key with the given description in `keyring`.
+    pub fn new(keyring: Keyring, description: &str) -> Result<Self> {
+        let description = CString::new(description).map_err(|_| Error::Enclave)?;
+        Ok(Self {
+            description,
+            keyring,
+        })
+    }
+
+    /// Finds the serial number of our key, if it exists.
+    fn search(&self) -> Result<Option<libc::c_long>> {
+        let serial = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_SEARCH,
+                self.keyring.spec(),
+                KEY_TYPE.as_ptr(),
+                self.description.as_ptr(),
+                0,
+            )
+        };
+
+        if serial < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(errno) if is_gone(errno) => Ok(None),
+                _ => Err(err.into()),
+            };
+        }
+
+        Ok(Some(serial))
+    }
+
+    fn read(&self, serial: libc::c_long) -> Result<Zeroizing<Vec<u8>>> {
+        let mut payload = Zeroizing::new(vec![]);
+        loop {
+            // The payload can change size between calls, so we keep going until it fits.
+            let len = unsafe {
+                libc::syscall(
+                    libc::SYS_keyctl,
+                    KEYCTL_READ,
+                    serial,
+                    payload.as_mut_ptr(),
+                    payload.len(),
+                )
+            };
+            if len < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            let len = len as usize;
+            if len <= payload.len() {
+                payload.truncate(len);
+                return Ok(payload);
+            }
+            // Growing in place could leave a copy behind in the old allocation.
+            payload = Zeroizing::new(vec![0; len]);
+        }
+    }
+
+    fn update(&self, serial: libc::c_long, payload: &[u8]) -> io::Result<()> {
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_UPDATE,
+                serial,
+                payload.as_ptr(),
+                payload.len(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn add(&self, payload: &[u8]) -> Result<()> {
+        let serial = unsafe {
+            libc::syscall(
+                libc::SYS_add_key,
+                KEY_TYPE.as_ptr(),
+                self.description.as_ptr(),
+                payload.as_ptr(),
+                payload.len(),
+                self.keyring.spec(),
+            )
+        };
+        if serial < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+/// Whether an error means the key no longer exists, as opposed to us not being allowed to use it.
+fn is_gone(errno: i32) -> bool {
+    matches!(errno, libc::ENOKEY | libc::EKEYREVOKED | libc::EKEYEXPIRED)
+}
+
+impl<const KEY_SZ: usize> Enclave<KEY_SZ> for KeyringEnclave {
+    fn is_initialized(&mut self) -> Result<bool> {
+        Ok(self.search()?.is_some())
+    }
+
+    fn get(&mut self) -> Result<Option<Root<KEY_SZ>>> {
+        match self.search()? {
+            Some(serial) => Ok(Some(
+                Root::decode(&self.read(serial)?).ok_or(Error::Enclave)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn replace(&mut self, root: &Root<KEY_SZ>) -> Result<()> {
+        let payload = root.encode();
+
+        // The key can be revoked or expire between the search and the update, in which case we
+        // start over with a new one.
+        match self.search()? {
+            Some(serial) => match self.update(serial, &payload) {
+                Err(err) if err.raw_os_error().is_some_and(is_gone) => self.add(&payload),
+                res => Ok(res?),
+            },
+            None => self.add(&payload),
+        }
+    }
+}
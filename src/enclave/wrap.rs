@@ -0,0 +1,311 @@
+use super::direct::DirectFile;
+use crate::{
+    error::{Error, Result},
+    seal::Sealer,
+    secret::Secret,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use log::warn;
+use rand::{CryptoRng, RngCore};
+use zeroize::Zeroizing;
+
+const WRAPPED_MAGIC: [u8; 8] = *b"SDBTKEYS";
+const MASTER_KEY_SZ: usize = 32;
+const SALT_SZ: usize = 16;
+
+/// The number of keyslots in a wrapped enclave.
+pub const KEYSLOTS: usize = 8;
+
+/// Argon2id parameters for new keyslots: 64 MiB of memory and 3 passes. These are also the most
+/// that a keyslot is allowed to ask for.
+const M_COST: u32 = 64 * 1024;
+const T_COST: u32 = 3;
+const P_COST: u32 = 1;
+
+/// A keyslot in use, as shown when listing them.
+#[derive(Clone, Copy, Debug)]
+pub struct KeyslotInfo {
+    pub index: usize,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+/// The master key wrapped under a key derived from one passphrase or keyfile.
+///
+/// Keyslots are laid out as `[salt][m_cost][t_cost][p_cost][nonce][ciphertext][tag]`. The
+/// ciphertext is bound to the keyslot's index and parameters, and an all-zero keyslot is free.
+#[derive(Clone)]
+struct Keyslot {
+    salt: [u8; SALT_SZ],
+    params: [u32; 3],
+    /// The sealed master key, as `[nonce][ciphertext][tag]`.
+    sealed: Vec<u8>,
+}
+
+impl Keyslot {
+    const SIZE: usize = SALT_SZ + 3 * 4 + MASTER_KEY_SZ + Sealer::OVERHEAD;
+
+    fn seal(
+        index: usize,
+        passphrase: &[u8],
+        master: &[u8; MASTER_KEY_SZ],
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> Result<Self> {
+        let mut keyslot = Self {
+            salt: [0; SALT_SZ],
+            params: [M_COST, T_COST, P_COST],
+            sealed: vec![],
+        };
+        rng.fill_bytes(&mut keyslot.salt);
+
+        let kek = derive(passphrase, &keyslot.salt, keyslot.params)?;
+        keyslot.sealed = Sealer::new(&kek).seal(master, &keyslot.aad(index), rng);
+
+        Ok(keyslot)
+    }
+
+    /// Unwraps the master key, returning `None` if the passphrase doesn't fit this keyslot.
+    ///
+    /// The parameters aren't authenticated until the key is derived, so a keyslot that asks for
+    /// more than we'd ever write is skipped instead of derived.
+    fn unseal(
+        &self,
+        index: usize,
+        passphrase: &[u8],
+    ) -> Result<Option<Secret<[u8; MASTER_KEY_SZ]>>> {
+        let [m, t, p] = self.params;
+        if m > M_COST || t > T_COST || p > P_COST {
+            warn!("skipping keyslot {index}, its argon2id costs m={m} t={t} p={p} are too high");
+            return Ok(None);
+        }
+
+        let kek = derive(passphrase, &self.salt, self.params)?;
+        Ok(Sealer::new(&kek)
+            .open(&self.sealed, &self.aad(index))
+            .map(|master| Secret::new(master.as_slice().try_into().unwrap())))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(Self::SIZE);
+        raw.extend_from_slice(&self.salt);
+        for param in self.params {
+            raw.extend_from_slice(&param.to_le_bytes());
+        }
+        raw.extend_from_slice(&self.sealed);
+        raw
+    }
+
+    /// Decodes a keyslot, returning `None` if it's free.
+    fn decode(raw: &[u8]) -> Option<Self> {
+        if raw.iter().all(|&b| b == 0) {
+            return None;
+        }
+
+        let param = |i: usize| {
+            let start = SALT_SZ + 4 * i;
+            u32::from_le_bytes(raw[start..start + 4].try_into().unwrap())
+        };
+
+        Some(Self {
+            salt: raw[..SALT_SZ].try_into().unwrap(),
+            params: [param(0), param(1), param(2)],
+            sealed: raw[SALT_SZ + 3 * 4..Self::SIZE].into(),
+        })
+    }
+
+    fn aad(&self, index: usize) -> Vec<u8> {
+        let mut aad = WRAPPED_MAGIC.to_vec();
+        aad.extend_from_slice(&(index as u64).to_le_bytes());
+        aad.extend_from_slice(&self.encode()[..SALT_SZ + 3 * 4]);
+        aad
+    }
+}
+
+/// Wraps root records under a random master key, which is itself wrapped once per passphrase or
+/// keyfile in a table of keyslots, like LUKS.
+///
+/// A wrapped enclave starts with a header laid out as `[magic][keyslot; KEYSLOTS]`. Each slot
+/// then holds `[nonce][ciphertext][tag]`, an AES-256-GCM encryption of the plain slot under the
+/// master key, bound to the slot's index. Any keyslot unlocks the enclave, and adding or removing
+/// one leaves the slots untouched.
+pub(crate) struct Wrapper {
+    master: Secret<[u8; MASTER_KEY_SZ]>,
+    keyslots: Vec<Option<Keyslot>>,
+    sealer: Sealer,
+}
+
+impl Wrapper {
+    pub const HEADER_SIZE: usize = 8 + KEYSLOTS * Keyslot::SIZE;
+    pub const OVERHEAD: usize = Sealer::OVERHEAD;
+
+    /// Locks the master key in memory again after a fork.
+    pub fn relock(&self) {
+        self.master.relock();
+    }
+
+    /// Whether an enclave's contents start with a wrapped header.
+    pub fn is_wrapped(raw: &[u8]) -> bool {
+        raw.starts_with(&WRAPPED_MAGIC)
+    }
+
+    /// Creates a wrapper for a new enclave with a fresh master key in the first keyslot.
+    pub fn create(passphrase: &[u8], rng: &mut (impl RngCore + CryptoRng)) -> Result<Self> {
+        let mut master = Secret::new([0; MASTER_KEY_SZ]);
+        rng.fill_bytes(&mut *master);
+
+        let mut wrapper = Self {
+            sealer: Sealer::new(&master),
+            master,
+            keyslots: vec![None; KEYSLOTS],
+        };
+        wrapper.keyslots[0] = Some(Keyslot::seal(0, passphrase, &wrapper.master, rng)?);
+
+        Ok(wrapper)
+    }
+
+    /// Opens the wrapper described by an enclave's header with whichever keyslot the passphrase
+    /// fits.
+    pub fn open(passphrase: &[u8], raw: &[u8]) -> Result<Self> {
+        if raw.len() < Self::HEADER_SIZE || !raw.starts_with(&WRAPPED_MAGIC) {
+            return Err(Error::Enclave);
+        }
+
+        let keyslots: Vec<_> = raw[8..Self::HEADER_SIZE]
+            .chunks_exact(Keyslot::SIZE)
+            .map(Keyslot::decode)
+            .collect();
+
+        for (index, keyslot) in keyslots.iter().enumerate() {
+            let Some(keyslot) = keyslot else {
+                continue;
+            };
+
+            if let Some(master) = keyslot.unseal(index, passphrase)? {
+                return Ok(Self {
+                    sealer: Sealer::new(&master),
+                    master,
+                    keyslots,
+                });
+            }
+        }
+
+        Err(Error::Passphrase)
+    }
+
+    pub fn header(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(Self::HEADER_SIZE);
+        header.extend_from_slice(&WRAPPED_MAGIC);
+        for index in 0..KEYSLOTS {
+            header.extend_from_slice(&self.keyslot(index));
+        }
+        header
+    }
+
+    /// The raw contents of a keyslot, which are all zeros once it's freed.
+    pub fn keyslot(&self, index: usize) -> Vec<u8> {
+        match &self.keyslots[index] {
+            Some(keyslot) => keyslot.encode(),
+            None => vec![0; Keyslot::SIZE],
+        }
+    }
+
+    pub fn keyslot_offset(index: usize) -> u64 {
+        (8 + index * Keyslot::SIZE) as u64
+    }
+
+    pub fn keyslots(&self) -> Vec<KeyslotInfo> {
+        self.keyslots
+            .iter()
+            .enumerate()
+            .filter_map(|(index, keyslot)| {
+                keyslot.as_ref().map(|keyslot| KeyslotInfo {
+                    index,
+                    m_cost: keyslot.params[0],
+                    t_cost: keyslot.params[1],
+                    p_cost: keyslot.params[2],
+                })
+            })
+            .collect()
+    }
+
+    /// Wraps the master key under another passphrase in the first free keyslot, returning its
+    /// index.
+    pub fn add(
+        &mut self,
+        passphrase: &[u8],
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> Result<usize> {
+        let index = self
+            .keyslots
+            .iter()
+            .position(Option::is_none)
+            .ok_or_else(|| Error::Keyslot(format!("all {KEYSLOTS} keyslots are in use")))?;
+
+        self.keyslots[index] = Some(Keyslot::seal(index, passphrase, &self.master, rng)?);
+        Ok(index)
+    }
+
+    /// Frees a keyslot. The last keyslot can't be removed, since nothing could unlock the
+    /// enclave after that.
+    pub fn remove(&mut self, index: usize) -> Result<()> {
+        if !matches!(self.keyslots.get(index), Some(Some(_))) {
+            return Err(Error::Keyslot(format!("keyslot {index} isn't in use")));
+        }
+        if self.keyslots.iter().flatten().count() == 1 {
+            return Err(Error::Keyslot(format!(
+                "keyslot {index} is the only one that can unlock the enclave"
+            )));
+        }
+
+        self.keyslots[index] = None;
+        Ok(())
+    }
+
+    pub fn seal(&self, index: u64, slot: &[u8], rng: &mut (impl RngCore + CryptoRng)) -> Vec<u8> {
+        self.sealer
+            .seal(slot, &Self::aad(&WRAPPED_MAGIC, index), rng)
+    }
+
+    /// Decrypts a sealed slot, returning `None` if it's empty, torn, or has been tampered with.
+    pub fn unseal(&self, index: u64, raw: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+        self.sealer.open(raw, &Self::aad(&WRAPPED_MAGIC, index))
+    }
+
+    fn aad(header: &[u8], index: u64) -> Vec<u8> {
+        [header, &index.to_le_bytes()].concat()
+    }
+}
+
+fn derive(passphrase: &[u8], salt: &[u8], [m, t, p]: [u32; 3]) -> Result<Zeroizing<[u8; 32]>> {
+    let params = Params::new(m, t, p, Some(32)).map_err(|_| Error::Enclave)?;
+    let mut kek = Zeroizing::new([0; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase, salt, kek.as_mut_slice())
+        .map_err(|_| Error::Enclave)?;
+    Ok(kek)
+}
+
+/// Sets up the wrapper for an enclave file, writing a fresh header to it if it's new.
+///
+/// Returns `None` for plain enclaves, which are only allowed when no passphrase is given.
+pub(crate) fn open_wrapper(
+    file: &mut DirectFile,
+    passphrase: Option<&[u8]>,
+    rng: &mut (impl RngCore + CryptoRng),
+) -> Result<Option<Wrapper>> {
+    let raw = file.read_all()?;
+
+    match passphrase {
+        None if Wrapper::is_wrapped(&raw) => Err(Error::PassphraseRequired),
+        None => Ok(None),
+        // New enclaves are preallocated, so they're all zeros rather than empty.
+        Some(passphrase) if raw.iter().all(|&b| b == 0) => {
+            let wrapper = Wrapper::create(passphrase, rng)?;
+            file.write_at(0, &wrapper.header())?;
+            Ok(Some(wrapper))
+        }
+        Some(passphrase) if Wrapper::is_wrapped(&raw) => Ok(Some(Wrapper::open(passphrase, &raw)?)),
+        Some(_) => Err(Error::NotWrapped),
+    }
+}
@@ -15,12 +15,73 @@ pub enum Error {
     #[error("deallocation error: {0}")]
     Dealloc(u64),
 
+    #[error("file too large")]
+    TooLarge,
+
     #[error("storage error")]
     Storage,
 
     #[error("enclave error")]
     Enclave,
 
+    #[error("wrong passphrase for the enclave")]
+    Passphrase,
+
+    #[error("enclave is passphrase-protected, but no passphrase was given")]
+    PassphraseRequired,
+
+    #[error("enclave isn't passphrase-protected")]
+    NotWrapped,
+
+    #[error("enclave can't be securely overwritten: {0}")]
+    InsecureEnclave(String),
+
+    #[error("enclave device is too small: it has {size} bytes, but the slots need {needed}")]
+    EnclaveTooSmall { size: u64, needed: u64 },
+
+    #[error("enclave device holds something other than an enclave; zero it before its first use")]
+    DeviceNotZeroed,
+
+    #[error("enclave is in use, by a mount or another command")]
+    EnclaveInUse,
+
+    #[error("secret sharing error: {0}")]
+    Shares(String),
+
+    #[error("keyslot error: {0}")]
+    Keyslot(String),
+
+    #[error("key agent error: {0}")]
+    Agent(String),
+
+    #[error("no committed manifest matches the enclave key")]
+    Manifest,
+
+    #[error(
+        "metadir was rolled back: enclave is at epoch {enclave}, metadir is at epoch {metadir}"
+    )]
+    Rollback { enclave: u64, metadir: u64 },
+
+    #[error("integrity check failed: {0}")]
+    Integrity(String),
+
+    #[error("journal is corrupt or has been tampered with")]
+    Journal,
+
+    #[error(
+        "can't build a key tree of degree {0}: sdbtree only builds trees of its default degree"
+    )]
+    Degree(usize),
+
+    #[error(
+        "metadir already holds a volume, but the enclave is empty: use the volume's enclave, or \
+         restore its key into this one with --restore-from-shares"
+    )]
+    Formatted,
+
+    #[error("superblock error: {0}")]
+    Superblock(String),
+
     #[error(transparent)]
     Serde(#[from] bincode::Error),
 }
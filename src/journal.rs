@@ -0,0 +1,148 @@
+use crate::{
+    blocks::BlockSet,
+    error::{Error, Result},
+    seal::Sealer,
+};
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+};
+
+const JOURNAL_KEY_INFO: &[u8] = b"sdbtreefs journal";
+
+/// A namespace or extent mutation made since the last persist.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Op {
+    Create {
+        path: String,
+    },
+    Unlink {
+        path: String,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    Link {
+        from: String,
+        to: String,
+    },
+    Symlink {
+        from: String,
+        to: String,
+    },
+    Extend {
+        path: String,
+        index: u64,
+    },
+    /// Which of a file's blocks have keys, once an `fsync` has made those keys durable.
+    Sync {
+        path: String,
+        blocks: BlockSet,
+    },
+}
+
+/// An encrypted, append-only log of namespace mutations for a single epoch.
+///
+/// Each record is laid out as `[len: u32][nonce][ciphertext]` and is authenticated with the epoch
+/// and its sequence number, so records can't be altered, reordered, dropped from the middle, or
+/// moved between epochs without detection. A record cut short by a crash is treated as the end of
+/// the journal, so truncating the journal isn't detected: it looks the same as a crash before the
+/// dropped records were written.
+pub(crate) struct Journal {
+    file: File,
+    sealer: Sealer,
+    epoch: u64,
+    seq: u64,
+}
+
+impl Journal {
+    /// Creates an empty journal for `epoch`, replacing any existing one.
+    pub fn create(path: &str, root_key: &[u8], epoch: u64) -> Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        Ok(Self {
+            file,
+            sealer: Sealer::derive(root_key, JOURNAL_KEY_INFO),
+            epoch,
+            seq: 0,
+        })
+    }
+
+    /// Opens the journal for `epoch`, returning it along with the operations it holds.
+    pub fn open(path: &str, root_key: &[u8], epoch: u64) -> Result<(Self, Vec<Op>)> {
+        let mut file = match File::options().read(true).write(true).open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Ok((Self::create(path, root_key, epoch)?, vec![]));
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut raw = vec![];
+        file.read_to_end(&mut raw)?;
+
+        let mut journal = Self {
+            file,
+            sealer: Sealer::derive(root_key, JOURNAL_KEY_INFO),
+            epoch,
+            seq: 0,
+        };
+
+        let mut ops = vec![];
+        let mut pos = 0;
+
+        while let Some(len) = raw
+            .get(pos..pos + 4)
+            .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+        {
+            let Some(record) = raw.get(pos + 4..pos + 4 + len) else {
+                break;
+            };
+            let ser = journal
+                .sealer
+                .open(record, &journal.aad())
+                .ok_or(Error::Journal)?;
+
+            ops.push(bincode::deserialize(&ser)?);
+            journal.seq += 1;
+            pos += 4 + len;
+        }
+
+        // Drop any torn record so that appends continue from the last complete one.
+        journal.file.set_len(pos as u64)?;
+        journal.file.seek(SeekFrom::End(0))?;
+
+        Ok((journal, ops))
+    }
+
+    /// Durably appends an operation to the journal.
+    pub fn append(&mut self, op: &Op, rng: &mut (impl RngCore + CryptoRng)) -> Result<()> {
+        let ser = bincode::serialize(op)?;
+        let sealed = self.sealer.seal(&ser, &self.aad(), rng);
+
+        let mut record = Vec::with_capacity(4 + sealed.len());
+        record.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+        record.extend_from_slice(&sealed);
+
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        self.seq += 1;
+
+        Ok(())
+    }
+
+    fn aad(&self) -> [u8; 16] {
+        let mut aad = [0; 16];
+        aad[..8].copy_from_slice(&self.epoch.to_le_bytes());
+        aad[8..].copy_from_slice(&self.seq.to_le_bytes());
+        aad
+    }
+}
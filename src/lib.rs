@@ -1,21 +1,36 @@
+pub mod agent;
+mod blocks;
+pub mod checkpoint;
+pub mod enclave;
 pub mod error;
+mod journal;
 mod localize;
+pub mod localizer;
 pub mod persist;
+pub mod recovery;
+mod seal;
+pub mod secret;
+pub mod shamir;
+mod superblock;
 pub mod utils;
 
 use allocator::{seq::SequentialAllocator, Allocator};
 use anyhow::{anyhow, Result};
+use blocks::BlockSet;
+use checkpoint::{CheckpointPolicy, Checkpointer};
 use core::ffi::*;
 use crypter::{openssl::Aes256Ctr, Crypter};
 use cryptio::iv::BlockIvCryptIo;
-use embedded_io::adapters::FromStd;
 use embedded_io::{
     blocking::{Read, Seek, Write},
     SeekFrom,
 };
+use enclave::{Enclave, FileEnclave};
 use error::{Error, Result as SDBResult};
 use fuse_sys::*;
-use localize::LocalizedBKeyTree;
+use journal::{Journal, Op};
+use localize::{Extents, LocalizedBKeyTree, NO_EXTENTS};
+use localizer::Localization;
 use log::*;
 use passthrough::Passthrough;
 use rand::{rngs::ThreadRng, CryptoRng, RngCore};
@@ -23,39 +38,62 @@ use sdbtree::{
     storage::{dir::DirectoryStorage, Storage},
     BKeyTree,
 };
+use secret::Secret;
 use serde::{Deserialize, Serialize};
 use std::env;
 use std::marker::PhantomData;
-use std::{collections::HashMap, fs::File};
+use std::{collections::HashMap, fs, ops::Range};
 use umask::Mode;
+use zeroize::Zeroizing;
 
-const AES256CTR_KEY_SZ: usize = 32;
+pub const AES256CTR_KEY_SZ: usize = 32;
 const DEFAULT_BLOCK_SIZE: usize = 4096;
-const DEFAULT_DEGREE: usize = 2;
 type Key<const N: usize> = [u8; N];
 
+/// Names a cipher in the superblock. Names have to stay the same across builds and compilers.
+pub trait CipherName {
+    const NAME: &'static str;
+}
+
+impl CipherName for Aes256Ctr {
+    const NAME: &'static str = "aes-256-ctr";
+}
+
 pub struct SDBTreeFs<
     A = SequentialAllocator<u64>,
     R = ThreadRng,
     S = DirectoryStorage,
     C = Aes256Ctr,
+    E = FileEnclave,
     const KEY_SZ: usize = AES256CTR_KEY_SZ,
     const BLOCK_SZ: usize = DEFAULT_BLOCK_SIZE,
 > where
     for<'de> A: Allocator<Id = u64> + Default + Serialize + Deserialize<'de>,
     R: RngCore + CryptoRng + Default,
     S: Storage<Id = u64>,
-    C: Crypter,
+    C: Crypter + CipherName,
+    E: Enclave<KEY_SZ>,
 {
+    epoch: u64,
     root_id: u64,
-    root_key: Key<KEY_SZ>,
+    root_key: Secret<Key<KEY_SZ>>,
     tree: BKeyTree<R, S, C, KEY_SZ>,
-    enclave: FromStd<File>,
+    enclave: E,
     metadir: String,
     mappings: HashMap<String, u64>,
     links: HashMap<u64, u64>,
+    blocks: HashMap<u64, BlockSet>,
+    extents: HashMap<u64, Extents>,
+    localization: Localization,
     inner: Passthrough,
     allocator: A,
+    checkpointer: Checkpointer,
+    journal: Option<Journal>,
+    check: bool,
+    repair: bool,
+    degree: Option<usize>,
+    lock_memory: bool,
+    relocked: bool,
 }
 
 impl SDBTreeFs {
@@ -65,18 +103,24 @@ impl SDBTreeFs {
         metadir: impl AsRef<str>,
     ) -> SDBResult<Self> {
         Self::custom(
-            enclave,
+            FileEnclave::open(enclave.as_ref())?,
             datadir,
             metadir.as_ref(),
             DirectoryStorage::new(metadir.as_ref()).map_err(|_| Error::Storage)?,
         )
     }
+}
 
+impl<E> SDBTreeFs<SequentialAllocator<u64>, ThreadRng, DirectoryStorage, Aes256Ctr, E>
+where
+    E: Enclave<AES256CTR_KEY_SZ> + 'static,
+{
     pub fn options() -> SDBTreeFsBuilder<
         SequentialAllocator<u64>,
         ThreadRng,
         DirectoryStorage,
         Aes256Ctr,
+        E,
         AES256CTR_KEY_SZ,
         DEFAULT_BLOCK_SIZE,
     > {
@@ -84,15 +128,17 @@ impl SDBTreeFs {
     }
 }
 
-impl<A, R, S, C, const KEY_SZ: usize, const BLOCK_SZ: usize> SDBTreeFs<A, R, S, C, KEY_SZ, BLOCK_SZ>
+impl<A, R, S, C, E, const KEY_SZ: usize, const BLOCK_SZ: usize>
+    SDBTreeFs<A, R, S, C, E, KEY_SZ, BLOCK_SZ>
 where
     for<'de> A: Allocator<Id = u64> + Default + Serialize + Deserialize<'de> + 'static,
     R: RngCore + CryptoRng + Default + 'static,
     S: Storage<Id = u64> + 'static,
-    C: Crypter + 'static,
+    C: Crypter + CipherName + 'static,
+    E: Enclave<KEY_SZ> + 'static,
 {
     pub fn custom(
-        enclave: impl AsRef<str>,
+        enclave: E,
         datadir: impl AsRef<str>,
         metadir: impl AsRef<str>,
         storage: S,
@@ -100,16 +146,28 @@ where
         Ok(Self::custom_options().build(enclave, datadir, metadir, storage)?)
     }
 
-    pub fn custom_options() -> SDBTreeFsBuilder<A, R, S, C, KEY_SZ, BLOCK_SZ> {
+    pub fn custom_options() -> SDBTreeFsBuilder<A, R, S, C, E, KEY_SZ, BLOCK_SZ> {
         SDBTreeFsBuilder::new()
     }
 
     pub fn mount(mut self, mount: impl AsRef<str>) -> Result<()> {
-        // Before we mount, we can try to load state.
+        // Before we mount, we can try to load state. Otherwise, we format the volume and commit
+        // an initial epoch so that there's a root key in the enclave to journal against, as long
+        // as that wouldn't clobber a volume whose key just isn't in this enclave.
         if self.is_loadable()? {
             self.load()?;
+        } else if self.is_formatted() {
+            return Err(Error::Formatted.into());
+        } else {
+            self.persist_superblock()?;
+            self.persist()?;
         }
 
+        // State gets persisted in `destroy`, which libfuse only reaches on a clean exit.
+        utils::restore_exit_signals();
+
+        self.checkpointer.arm(mount.as_ref())?;
+
         let exec = env::args().next().unwrap().to_string();
 
         let mut args = vec![exec.as_str(), mount.as_ref()];
@@ -124,29 +182,369 @@ where
             .map_err(|err| anyhow!("unexpected FUSE error: {err}"))
     }
 
+    /// Takes the memory locks again, since they don't survive libfuse forking into the background.
+    fn relock(&mut self) {
+        self.relocked = true;
+
+        if self.lock_memory {
+            if let Err(err) = utils::lock_memory() {
+                error!("failed to lock memory: {err}");
+            }
+        }
+        self.root_key.relock();
+        self.enclave.relock();
+    }
+
     fn canonicalize(&self, path: &str) -> String {
         self.inner.canonicalize(path).to_string_lossy().to_string()
     }
 
-    fn localize(id: u64, block: u64) -> u64 {
-        id << 20 | (block & ((1 << 20) - 1))
+    /// The size of a file's contents given its on-disk size, which includes a padding IV for
+    /// every block.
+    fn unpadded_size(raw_size: u64) -> u64 {
+        let padded_block_size = (BLOCK_SZ + C::iv_length()) as u64;
+        let padded_blocks = raw_size.div_ceil(padded_block_size);
+        raw_size - padded_blocks * C::iv_length() as u64
+    }
+
+    /// The on-disk size of a file with `size` bytes of contents.
+    fn padded_size(size: u64) -> u64 {
+        let blocks = size.div_ceil(BLOCK_SZ as u64);
+        size + blocks * C::iv_length() as u64
+    }
+
+    fn file_size(ipath: &str) -> Result<u64> {
+        Ok(Self::unpadded_size(fs::metadata(ipath)?.len()))
+    }
+
+    /// The largest file size whose on-disk size still fits in an `off_t`.
+    fn max_size() -> u64 {
+        Self::unpadded_size(off_t::MAX as u64)
+    }
+
+    fn read_at(&mut self, ipath: &str, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let id = *self
+            .mappings
+            .get(ipath)
+            .ok_or(Error::Mapping(ipath.into()))?;
+
+        let end = (offset + buf.len() as u64).min(Self::file_size(ipath)?);
+        if offset >= end {
+            return Ok(0);
+        }
+
+        let io = Self::new_read_io(ipath)?;
+        let empty = BlockSet::default();
+        let blocks = self.blocks.get(&id).unwrap_or(&empty);
+
+        let extents = self.extents.get(&id).unwrap_or(&NO_EXTENTS);
+        let mut tree =
+            LocalizedBKeyTree::new(self.localization.localizer(), extents, &mut self.tree);
+        let mut reader = BlockIvCryptIo::<
+            _,
+            LocalizedBKeyTree<'_, R, S, C, KEY_SZ>,
+            R,
+            C,
+            BLOCK_SZ,
+            KEY_SZ,
+        >::new(io, &mut tree, R::default());
+
+        // Only blocks with keys get decrypted, holes just read as zeros.
+        let mut pos = offset;
+        while pos < end {
+            let (keyed, run_end) = blocks.run(pos / BLOCK_SZ as u64);
+            let run_end = run_end.saturating_mul(BLOCK_SZ as u64).min(end);
+            let chunk = &mut buf[(pos - offset) as usize..(run_end - offset) as usize];
+
+            if keyed {
+                reader.seek(SeekFrom::Start(pos))?;
+
+                let mut filled = 0;
+                while filled < chunk.len() {
+                    match reader.read(&mut chunk[filled..])? {
+                        0 => break,
+                        n => filled += n,
+                    }
+                }
+                chunk[filled..].fill(0);
+            } else {
+                chunk.fill(0);
+            }
+
+            pos = run_end;
+        }
+
+        Ok((end - offset) as usize)
+    }
+
+    fn write_at(&mut self, ipath: &str, buf: &[u8], offset: u64) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let id = *self
+            .mappings
+            .get(ipath)
+            .ok_or(Error::Mapping(ipath.into()))?;
+        let block_size = BLOCK_SZ as u64;
+
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .filter(|&end| end <= Self::max_size())
+            .ok_or(Error::TooLarge)?;
+
+        // Writing past the end turns the rest of a partial last block into zeros, which have to be
+        // covered by that block's key.
+        let size = Self::file_size(ipath)?;
+        if offset > size && size % block_size != 0 {
+            self.write_zeros(ipath, size, offset.min(size.next_multiple_of(block_size)))?;
+        }
+        let size = Self::file_size(ipath)?;
+
+        // Holes don't have a key to decrypt their old contents with, so partial writes to them get
+        // padded out with the zeros the hole holds.
+        let first = offset / block_size;
+        let last = (end - 1) / block_size;
+        let blocks = self.blocks.entry(id).or_default();
+
+        let start = if blocks.contains(first) {
+            offset
+        } else {
+            first * block_size
+        };
+        let padded_end = if blocks.contains(last) {
+            end
+        } else {
+            ((last + 1) * block_size).min(size.max(end))
+        };
+
+        // The padded copy holds plaintext, so it's wiped once it's written.
+        let padded;
+        let data: &[u8] = if start == offset && padded_end == end {
+            buf
+        } else {
+            let mut zeros = Zeroizing::new(vec![0; (padded_end - start) as usize]);
+            zeros[(offset - start) as usize..(end - start) as usize].copy_from_slice(buf);
+            padded = zeros;
+            padded.as_slice()
+        };
+
+        // Every block we write needs an extent to hold its key.
+        let localizer = self.localization.localizer();
+        let first_extent = localizer.extent_index(first);
+        let last_extent = localizer.extent_index((padded_end - 1) / block_size);
+        for index in first_extent..=last_extent {
+            if !self.has_extent(id, index) {
+                self.record(Op::Extend {
+                    path: ipath.into(),
+                    index,
+                })?;
+                if !self.has_extent(id, index) {
+                    return Err(Error::TooLarge.into());
+                }
+            }
+        }
+
+        let io = Self::new_write_io(ipath)?;
+        let extents = self.extents.get(&id).unwrap_or(&NO_EXTENTS);
+        let mut tree =
+            LocalizedBKeyTree::new(self.localization.localizer(), extents, &mut self.tree);
+        let mut writer = BlockIvCryptIo::<
+            _,
+            LocalizedBKeyTree<'_, R, S, C, KEY_SZ>,
+            R,
+            C,
+            BLOCK_SZ,
+            KEY_SZ,
+        >::new(io, &mut tree, R::default());
+
+        writer.seek(SeekFrom::Start(start))?;
+        writer.write_all(data)?;
+
+        self.blocks
+            .entry(id)
+            .or_default()
+            .insert(first..padded_end.div_ceil(block_size));
+
+        Ok(buf.len())
+    }
+
+    /// Writes encrypted zeros over `[start, end)`, giving every block it touches a fresh key.
+    fn write_zeros(&mut self, ipath: &str, start: u64, end: u64) -> Result<()> {
+        const CHUNK_SZ: u64 = 1 << 20;
+
+        let zeros = vec![0; CHUNK_SZ.min(end.saturating_sub(start)) as usize];
+        let mut offset = start;
+        while offset < end {
+            let len = CHUNK_SZ.min(end - offset) as usize;
+            offset += self.write_at(ipath, &zeros[..len], offset)? as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Readies a file's contents to grow from `old_size` to `size`. The rest of a partial last
+    /// block has to read back as zeros, everything past it is a hole.
+    fn grow(&mut self, ipath: &str, old_size: u64, size: u64) -> Result<()> {
+        let tail_end = size.min(old_size.next_multiple_of(BLOCK_SZ as u64));
+        self.write_zeros(ipath, old_size, tail_end)
+    }
+
+    /// Destroys the keys of a file's blocks in `range`, turning them into holes.
+    fn remove_block_keys(&mut self, id: u64, range: Range<u64>) -> SDBResult<()> {
+        let Some(blocks) = self.blocks.get_mut(&id) else {
+            return Ok(());
+        };
+
+        if blocks.is_empty() {
+            return Ok(());
+        }
+
+        // Only runs of blocks with keys are removed, since each block of a run can take its own
+        // traversal depending on the localizer.
+        let extents = self.extents.get(&id).unwrap_or(&NO_EXTENTS);
+        let mut tree =
+            LocalizedBKeyTree::new(self.localization.localizer(), extents, &mut self.tree);
+        for keyed in blocks.ranges() {
+            let run = keyed.start.max(range.start)..keyed.end.min(range.end);
+            tree.remove_range(run).map_err(|_| Error::Storage)?;
+        }
+
+        blocks.remove(range);
+        Ok(())
+    }
+
+    fn remove_keys(&mut self, id: u64) -> SDBResult<()> {
+        self.remove_block_keys(id, 0..u64::MAX)?;
+        self.blocks.remove(&id);
+
+        // Files migrated from before extents use their own ID as their first extent, and that's
+        // deallocated along with the file.
+        for extent in self.extents.remove(&id).unwrap_or_default().into_values() {
+            if extent != id {
+                self.allocator
+                    .dealloc(extent)
+                    .map_err(|_| Error::Dealloc(extent))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn has_extent(&self, id: u64, index: u64) -> bool {
+        self.extents
+            .get(&id)
+            .is_some_and(|extents| extents.contains_key(&index))
+    }
+
+    /// Journals a namespace mutation, then applies it.
+    fn record(&mut self, op: Op) -> SDBResult<()> {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.append(&op, &mut R::default())?;
+        }
+        self.apply(&op)
+    }
+
+    /// Applies a namespace mutation to the mappings and links, or allocates an extent.
+    ///
+    /// This is also used to replay the journal, which relies on the allocator handing out the
+    /// same IDs when given the same sequence of allocations and deallocations.
+    fn apply(&mut self, op: &Op) -> SDBResult<()> {
+        match op {
+            Op::Create { path } => {
+                let id = self.allocator.alloc().map_err(|_| Error::Alloc)?;
+
+                self.mappings.insert(path.clone(), id);
+                *self.links.entry(id).or_insert(0) += 1;
+            }
+            Op::Unlink { path } => {
+                let id = self
+                    .mappings
+                    .remove(path)
+                    .ok_or(Error::Mapping(path.clone()))?;
+                let links = self.links.entry(id).or_insert(1);
+
+                *links -= 1;
+
+                if *links == 0 {
+                    self.allocator.dealloc(id).map_err(|_| Error::Dealloc(id))?;
+                    self.remove_keys(id)?;
+                }
+            }
+            Op::Rename { from, to } => {
+                let id = self
+                    .mappings
+                    .remove(from)
+                    .ok_or(Error::Mapping(from.clone()))?;
+
+                self.mappings.insert(to.clone(), id);
+            }
+            Op::Link { from, to } | Op::Symlink { from, to } => {
+                let id = *self
+                    .mappings
+                    .get(from)
+                    .ok_or(Error::Mapping(from.clone()))?;
+
+                self.mappings.insert(to.clone(), id);
+                *self.links.entry(id).or_insert(0) += 1;
+            }
+            Op::Extend { path, index } => {
+                let id = *self
+                    .mappings
+                    .get(path)
+                    .ok_or(Error::Mapping(path.clone()))?;
+                let extent = self.allocator.alloc().map_err(|_| Error::Alloc)?;
+
+                // Extents past the key space are handed straight back, leaving the write that
+                // wanted it to fail.
+                if extent > self.localization.localizer().max_extent() {
+                    self.allocator
+                        .dealloc(extent)
+                        .map_err(|_| Error::Dealloc(extent))?;
+                } else {
+                    self.extents.entry(id).or_default().insert(*index, extent);
+                }
+            }
+            Op::Sync { path, blocks } => {
+                let id = *self
+                    .mappings
+                    .get(path)
+                    .ok_or(Error::Mapping(path.clone()))?;
+
+                self.blocks.insert(id, blocks.clone());
+            }
+        }
+
+        Ok(())
     }
 }
 
-impl<A, R, S, C, const KEY_SZ: usize, const BLOCK_SZ: usize> UnthreadedFileSystem
-    for SDBTreeFs<A, R, S, C, KEY_SZ, BLOCK_SZ>
+impl<A, R, S, C, E, const KEY_SZ: usize, const BLOCK_SZ: usize> UnthreadedFileSystem
+    for SDBTreeFs<A, R, S, C, E, KEY_SZ, BLOCK_SZ>
 where
     for<'de> A: Allocator<Id = u64> + Default + Serialize + Deserialize<'de> + 'static,
     R: RngCore + CryptoRng + Default + 'static,
     S: Storage<Id = u64> + 'static,
-    C: Crypter + 'static,
+    C: Crypter + CipherName + 'static,
+    E: Enclave<KEY_SZ> + 'static,
 {
+    fn destroy(&mut self) {
+        debug!("destroy");
+
+        // This is our last chance to save the tree, mappings, links, and allocator.
+        if let Err(err) = self.persist().and_then(|_| self.mark_clean()) {
+            error!("failed to persist state on unmount: {err}");
+        }
+    }
+
     fn getattr(
         &mut self,
         path: &str,
         mut stbuf: Option<&mut fuse_sys::stat>,
         fi: Option<&mut fuse_sys::fuse_file_info>,
     ) -> Result<i32> {
+        self.checkpoint(false)?;
+
         let raw: *mut stat = *stbuf.as_mut().unwrap() as *mut _;
         let res = self.inner.getattr(path, stbuf, fi)?;
 
@@ -155,10 +553,7 @@ where
             let mode = unsafe { (*raw).st_mode };
             let raw_size = unsafe { (*raw).st_size };
             if mode & libc::S_IFMT == libc::S_IFREG {
-                let padded_block_size = (BLOCK_SZ + C::iv_length()) as i64;
-                let padded_blocks = (raw_size + padded_block_size - 1) / padded_block_size;
-                let iv_size = padded_blocks * C::iv_length() as i64;
-                let size = raw_size - iv_size;
+                let size = Self::unpadded_size(raw_size as u64) as i64;
                 debug!("getattr: path = {path}, res = {res}, size = {size}");
                 unsafe {
                     (*raw).st_size = size;
@@ -186,29 +581,11 @@ where
 
         let res = self.inner.unlink(path)?;
         if res == 0 {
-            let id = self
-                .mappings
-                .remove(&self.canonicalize(path))
-                .ok_or(Error::Mapping(self.canonicalize(path)))?;
-            let links = self.links.entry(id).or_insert(1);
-
-            *links -= 1;
-
-            if *links == 0 {
-                self.allocator.dealloc(id).map_err(|_| Error::Dealloc(id))?;
-
-                // This is super jank, but we'll just try to remove all the keys.
-                for block in 0.. {
-                    if self
-                        .tree
-                        .remove(&Self::localize(id, block))
-                        .map_err(|_| Error::Storage)?
-                        .is_none()
-                    {
-                        break;
-                    }
-                }
-            }
+            self.record(Op::Unlink {
+                path: self.canonicalize(path),
+            })?;
+
+            self.checkpoint(true)?;
         }
 
         Ok(res)
@@ -224,16 +601,12 @@ where
 
         let res = self.inner.symlink(from, to)?;
         if res == 0 {
-            let from_ipath = self.canonicalize(from);
-            let to_ipath = self.canonicalize(to);
+            self.record(Op::Symlink {
+                from: self.canonicalize(from),
+                to: self.canonicalize(to),
+            })?;
 
-            let id = *self
-                .mappings
-                .get(&from_ipath)
-                .ok_or(Error::Mapping(from_ipath))?;
-
-            self.mappings.insert(to_ipath, id);
-            *self.links.entry(id).or_insert(0) += 1;
+            self.checkpoint(true)?;
         }
 
         Ok(res)
@@ -244,15 +617,12 @@ where
 
         let res = self.inner.rename(from, to, flags)?;
         if res == 0 {
-            let from_ipath = self.canonicalize(from);
-            let to_ipath = self.canonicalize(to);
-
-            let id = self
-                .mappings
-                .remove(&from_ipath)
-                .ok_or(Error::Mapping(from_ipath))?;
+            self.record(Op::Rename {
+                from: self.canonicalize(from),
+                to: self.canonicalize(to),
+            })?;
 
-            self.mappings.insert(to_ipath, id);
+            self.checkpoint(true)?;
         }
 
         Ok(res)
@@ -263,16 +633,12 @@ where
 
         let res = self.inner.link(from, to)?;
         if res == 0 {
-            let from_ipath = self.canonicalize(from);
-            let to_ipath = self.canonicalize(to);
+            self.record(Op::Link {
+                from: self.canonicalize(from),
+                to: self.canonicalize(to),
+            })?;
 
-            let id = *self
-                .mappings
-                .get(&from_ipath)
-                .ok_or(Error::Mapping(from_ipath))?;
-
-            self.mappings.insert(to_ipath, id);
-            *self.links.entry(id).or_insert(0) += 1;
+            self.checkpoint(true)?;
         }
 
         Ok(res)
@@ -294,50 +660,63 @@ where
         self.inner.chown(path, uid, gid, fi)
     }
 
-    // fn truncate(
-    //     &mut self,
-    //     path: &str,
-    //     size: off_t,
-    //     fi: Option<&mut fuse_file_info>,
-    // ) -> Result<i32> {
-    //     debug!("truncate: path = {path}, size = {size}");
-
-    //     let size = size as u64;
-    //     let ipath = self.inode_path(path);
-
-    //     let khf_id = *self
-    //         .mappings
-    //         .get(&ipath)
-    //         .ok_or(Error::MissingKhf(ipath.clone()))?;
-
-    //     // Number of bytes past a block.
-    //     let extra = size % BLOCK_SZ as u64;
-
-    //     // Need to rewrite the extra bytes.
-    //     if extra > 0 {
-    //         let mut io = self.new_rw_io(&ipath)?;
-    //         let mut buf = vec![0; extra as usize];
-    //         let offset = (size / BLOCK_SZ as u64) * BLOCK_SZ as u64;
-
-    //         // Read in the extra bytes.
-    //         io.seek(SeekFrom::Start(offset))?;
-    //         io.read(&mut buf)?;
-
-    //         // Write the extra bytes.
-    //         io.seek(SeekFrom::Start(offset))?;
-    //         io.write(&buf)?;
-    //     }
-
-    //     // Truncate the forest Not needed for security, but nice for efficiency.
-    //     let keys = (size + (BLOCK_SZ as u64 - 1)) / BLOCK_SZ as u64;
-    //     self.get_mut_inode_khf(&ipath)?
-    //         .ok_or(Error::MissingKhf(ipath))?
-    //         .truncate(keys);
-
-    //     // Update the `Khf` and truncate the inode.
-    //     self.master_khf.update(khf_id)?;
-    //     self.inner.truncate(path, size as i64, fi)
-    // }
+    fn truncate(
+        &mut self,
+        path: &str,
+        size: off_t,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        debug!("truncate: path = {path}, size = {size}");
+
+        if size < 0 {
+            return Ok(-libc::EINVAL);
+        }
+        let size = size as u64;
+        if size > Self::max_size() {
+            return Ok(-libc::EFBIG);
+        }
+
+        let ipath = self.canonicalize(path);
+
+        let id = *self
+            .mappings
+            .get(&ipath)
+            .ok_or(Error::Mapping(ipath.clone()))?;
+        let old_size = Self::file_size(&ipath)?;
+        let block_size = BLOCK_SZ as u64;
+
+        if size < old_size {
+            // Number of bytes past a block.
+            let extra = size % block_size;
+
+            // Need to re-encrypt the extra bytes under a fresh key, since the old key also covers
+            // the bytes being cut off. Holes have nothing to re-encrypt.
+            let keyed = self
+                .blocks
+                .get(&id)
+                .is_some_and(|blocks| blocks.contains(size / block_size));
+            if extra > 0 && keyed {
+                let mut buf = Zeroizing::new(vec![0; extra as usize]);
+                let offset = size - extra;
+                self.read_at(&ipath, &mut buf, offset)?;
+                self.write_at(&ipath, &buf, offset)?;
+            }
+
+            // Destroy the keys of every block past the new end.
+            self.remove_block_keys(id, size.div_ceil(block_size)..u64::MAX)?;
+        } else if size > old_size {
+            self.grow(&ipath, old_size, size)?;
+        }
+
+        // Truncate the inode, accounting for the IVs.
+        let res = self
+            .inner
+            .truncate(path, Self::padded_size(size) as off_t, fi)?;
+
+        self.checkpoint(true)?;
+
+        Ok(res)
+    }
 
     fn open(&mut self, path: &str, fi: Option<&mut fuse_file_info>) -> Result<i32> {
         debug!("open: path = {path}");
@@ -354,21 +733,7 @@ where
         debug!("read: path = {path}");
 
         let ipath = self.canonicalize(path);
-        let io = Self::new_read_io(&ipath)?;
-        let id = self.mappings.get(&ipath).ok_or(Error::Mapping(ipath))?;
-
-        let mut tree = LocalizedBKeyTree::new(*id, Self::localize, &mut self.tree);
-        let mut reader = BlockIvCryptIo::<
-            _,
-            LocalizedBKeyTree<'_, R, S, C, KEY_SZ>,
-            R,
-            C,
-            BLOCK_SZ,
-            KEY_SZ,
-        >::new(io, &mut tree, R::default());
-
-        reader.seek(SeekFrom::Start(offset as u64))?;
-        Ok(reader.read(buf)? as i32)
+        Ok(self.read_at(&ipath, buf, offset as u64)? as i32)
     }
 
     fn write(
@@ -385,25 +750,25 @@ where
         );
 
         let ipath = self.canonicalize(path);
-        let io = Self::new_write_io(&ipath)?;
-        let id = self.mappings.get(&ipath).ok_or(Error::Mapping(ipath))?;
+        let written = match self.write_at(&ipath, buf, offset as u64) {
+            Ok(written) => written,
+            Err(err) if matches!(err.downcast_ref(), Some(Error::TooLarge)) => {
+                return Ok(-libc::EFBIG)
+            }
+            Err(err) => return Err(err),
+        };
 
-        let mut tree = LocalizedBKeyTree::new(*id, Self::localize, &mut self.tree);
-        let mut writer = BlockIvCryptIo::<
-            _,
-            LocalizedBKeyTree<'_, R, S, C, KEY_SZ>,
-            R,
-            C,
-            BLOCK_SZ,
-            KEY_SZ,
-        >::new(io, &mut tree, R::default());
+        self.checkpoint(true)?;
 
-        writer.seek(SeekFrom::Start(offset as u64))?;
-        Ok(writer.write(buf)? as i32)
+        Ok(written as i32)
     }
 
     fn statfs(&mut self, path: &str, stbuf: Option<&mut statvfs>) -> Result<i32> {
         debug!("statfs: path = {path}");
+
+        // The checkpoint timer pokes us with these.
+        self.checkpoint(false)?;
+
         self.inner.statfs(path, stbuf)
     }
 
@@ -427,19 +792,32 @@ where
         let res = self.inner.fsync(path, isdatasync, fi)?;
         if res == 0 {
             let ipath = self.canonicalize(path);
-            let id = self.mappings.get(&ipath).ok_or(Error::Mapping(ipath))?;
-
-            // This is super jank, but we just need to find and persist the nodes containing the
-            // block keys for the inode.
-            for block in 0.. {
-                if !self
-                    .tree
-                    .persist_block(&Self::localize(*id, block))
-                    .map_err(|_| Error::Storage)?
-                {
-                    break;
+            let id = *self
+                .mappings
+                .get(&ipath)
+                .ok_or(Error::Mapping(ipath.clone()))?;
+
+            // Persist the nodes containing the inode's block keys.
+            if let Some(blocks) = self.blocks.get(&id) {
+                let extents = self.extents.get(&id).unwrap_or(&NO_EXTENTS);
+                let mut tree =
+                    LocalizedBKeyTree::new(self.localization.localizer(), extents, &mut self.tree);
+                for keyed in blocks.ranges() {
+                    tree.persist_range(keyed).map_err(|_| Error::Storage)?;
                 }
             }
+
+            // The keys are only found again after a crash if the blocks that have them are too,
+            // so those get journaled unless we're about to persist everything anyway.
+            if self.checkpointer.on_sync() {
+                self.persist()?;
+            } else {
+                let blocks = self.blocks.get(&id).cloned().unwrap_or_default();
+                self.record(Op::Sync {
+                    path: ipath,
+                    blocks,
+                })?;
+            }
         }
         Ok(res)
     }
@@ -477,11 +855,11 @@ where
 
         let res = self.inner.create(path, mode | 0o666, fi)?;
 
-        let ipath = self.canonicalize(path);
-        let id = self.allocator.alloc().map_err(|_| Error::Alloc)?;
+        self.record(Op::Create {
+            path: self.canonicalize(path),
+        })?;
 
-        self.mappings.insert(ipath, id);
-        *self.links.entry(id).or_insert(0) += 1;
+        self.checkpoint(true)?;
 
         Ok(res)
     }
@@ -523,34 +901,159 @@ where
         debug!("lock: path = {path}");
         self.inner.lock(path, fi, cmd, lock)
     }
+
+    fn fallocate(
+        &mut self,
+        path: &str,
+        mode: c_int,
+        offset: off_t,
+        length: off_t,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        debug!("fallocate: path = {path}, mode = {mode:#x}, offset = {offset}, length = {length}");
+
+        let ipath = self.canonicalize(path);
+        let size = Self::file_size(&ipath)?;
+        let block_size = BLOCK_SZ as u64;
+        let padded_block_size = (BLOCK_SZ + C::iv_length()) as u64;
+        let keep_size = mode & libc::FALLOC_FL_KEEP_SIZE != 0;
+        if offset < 0 || length <= 0 {
+            return Ok(-libc::EINVAL);
+        }
+        let start = offset as u64;
+        let Some(end) = start.checked_add(length as u64) else {
+            return Ok(-libc::EFBIG);
+        };
+
+        if !keep_size && end > Self::max_size() {
+            return Ok(-libc::EFBIG);
+        }
+
+        if mode & libc::FALLOC_FL_PUNCH_HOLE != 0 {
+            if !keep_size {
+                return Ok(-libc::EINVAL);
+            }
+
+            let end = end.min(size);
+            let id = *self
+                .mappings
+                .get(&ipath)
+                .ok_or(Error::Mapping(ipath.clone()))?;
+
+            // Blocks entirely inside the hole lose their keys and their space on disk.
+            let full = start.div_ceil(block_size)..end / block_size;
+            if !full.is_empty() {
+                let res = self.inner.fallocate(
+                    path,
+                    libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    (full.start * padded_block_size) as off_t,
+                    ((full.end - full.start) * padded_block_size) as off_t,
+                    fi,
+                )?;
+                if res != 0 {
+                    return Ok(res);
+                }
+                self.remove_block_keys(id, full.clone())?;
+            }
+
+            // Partially covered blocks at the edges need fresh keys, since their old keys also
+            // covered the punched bytes.
+            let edges = if full.start <= full.end {
+                [
+                    (start, full.start * block_size),
+                    (full.end * block_size, end),
+                ]
+            } else {
+                [(start, end), (end, end)]
+            };
+            for (edge_start, edge_end) in edges {
+                let keyed = self
+                    .blocks
+                    .get(&id)
+                    .is_some_and(|blocks| blocks.contains(edge_start / block_size));
+                if edge_start < edge_end && keyed {
+                    self.write_zeros(&ipath, edge_start, edge_end)?;
+                }
+            }
+        } else if mode & libc::FALLOC_FL_ZERO_RANGE != 0 {
+            // Rewriting the range re-keys every block it touches.
+            let end = if keep_size { end.min(size) } else { end };
+            self.write_zeros(&ipath, start, end)?;
+        } else if mode & !libc::FALLOC_FL_KEEP_SIZE == 0 {
+            // Reserve space for the padded blocks covering the range. We never let the inode grow
+            // here, since zeroed bytes on disk aren't valid encrypted blocks.
+            let raw_start = start / block_size * padded_block_size;
+            let raw_end = Self::padded_size(end);
+            let res = self.inner.fallocate(
+                path,
+                mode | libc::FALLOC_FL_KEEP_SIZE,
+                raw_start as off_t,
+                (raw_end - raw_start) as off_t,
+                fi,
+            )?;
+            if res != 0 {
+                return Ok(res);
+            }
+
+            // The file grows like it does when it's truncated, with holes past its old end, now
+            // that there's space for them.
+            if !keep_size && end > size {
+                self.grow(&ipath, size, end)?;
+                let res = self
+                    .inner
+                    .truncate(path, Self::padded_size(end) as off_t, None)?;
+                if res != 0 {
+                    return Ok(res);
+                }
+            }
+        } else {
+            return Ok(-libc::EOPNOTSUPP);
+        }
+
+        self.checkpoint(true)?;
+
+        Ok(0)
+    }
 }
 
-pub struct SDBTreeFsBuilder<A, R, S, C, const KEY_SZ: usize, const BLOCK_SZ: usize>
+pub struct SDBTreeFsBuilder<A, R, S, C, E, const KEY_SZ: usize, const BLOCK_SZ: usize>
 where
     for<'de> A: Allocator<Id = u64> + Default + Serialize + Deserialize<'de>,
     R: RngCore + CryptoRng + Default,
     S: Storage<Id = u64>,
-    C: Crypter,
+    C: Crypter + CipherName,
+    E: Enclave<KEY_SZ>,
 {
     debug: bool,
     foreground: bool,
-    degree: usize,
-    pd: PhantomData<(A, R, S, C)>,
+    degree: Option<usize>,
+    checkpoint: CheckpointPolicy,
+    check: bool,
+    repair: bool,
+    localization: Localization,
+    lock_memory: bool,
+    pd: PhantomData<(A, R, S, C, E)>,
 }
 
-impl<A, R, S, C, const KEY_SZ: usize, const BLOCK_SZ: usize>
-    SDBTreeFsBuilder<A, R, S, C, KEY_SZ, BLOCK_SZ>
+impl<A, R, S, C, E, const KEY_SZ: usize, const BLOCK_SZ: usize>
+    SDBTreeFsBuilder<A, R, S, C, E, KEY_SZ, BLOCK_SZ>
 where
     for<'de> A: Allocator<Id = u64> + Default + Serialize + Deserialize<'de>,
     R: RngCore + CryptoRng + Default,
     S: Storage<Id = u64>,
-    C: Crypter,
+    C: Crypter + CipherName,
+    E: Enclave<KEY_SZ>,
 {
     pub fn new() -> Self {
         Self {
             debug: true,
             foreground: true,
-            degree: DEFAULT_DEGREE,
+            degree: None,
+            checkpoint: CheckpointPolicy::default(),
+            check: false,
+            repair: false,
+            localization: Localization::default(),
+            lock_memory: false,
             pd: PhantomData,
         }
     }
@@ -565,39 +1068,79 @@ where
         self
     }
 
-    pub fn degree(mut self, degree: usize) -> Self {
+    /// Sets the degree of the key tree, or keeps sdbtree's default with `None`.
+    pub fn degree(mut self, degree: Option<usize>) -> Self {
         self.degree = degree;
         self
     }
 
+    pub fn checkpoint(mut self, policy: CheckpointPolicy) -> Self {
+        self.checkpoint = policy;
+        self
+    }
+
+    /// Checks the datadir against the metadata on every load, not just after an unclean unmount.
+    pub fn check(mut self, check: bool) -> Self {
+        self.check = check;
+        self
+    }
+
+    pub fn repair(mut self, repair: bool) -> Self {
+        self.repair = repair;
+        self
+    }
+
+    /// Sets how keys are placed in the tree. This only applies to new volumes, existing ones must
+    /// be mounted with the localization they were created with.
+    pub fn localization(mut self, localization: Localization) -> Self {
+        self.localization = localization;
+        self
+    }
+
+    /// Locks all memory once mounted, see [`utils::lock_memory`].
+    pub fn lock_memory(mut self, lock_memory: bool) -> Self {
+        self.lock_memory = lock_memory;
+        self
+    }
+
     pub fn build(
         self,
-        enclave: impl AsRef<str>,
+        enclave: E,
         datadir: impl AsRef<str>,
         metadir: impl AsRef<str>,
         storage: S,
-    ) -> SDBResult<SDBTreeFs<A, R, S, C, KEY_SZ, BLOCK_SZ>> {
+    ) -> SDBResult<SDBTreeFs<A, R, S, C, E, KEY_SZ, BLOCK_SZ>> {
+        // The sdbtree we build against only builds trees of its default degree.
+        if let Some(degree) = self.degree {
+            return Err(Error::Degree(degree));
+        }
+
         let root_key = utils::generate_key(&mut R::default());
 
         Ok(SDBTreeFs {
+            epoch: 0,
             root_id: 0,
+            tree: BKeyTree::with_storage(storage, *root_key).map_err(|_| Error::Storage)?,
             root_key,
-            tree: BKeyTree::with_storage(storage, root_key).map_err(|_| Error::Storage)?,
-            enclave: FromStd::new(
-                File::options()
-                    .read(true)
-                    .write(true)
-                    .create(true)
-                    .open(enclave.as_ref())?,
-            ),
+            enclave,
             metadir: metadir.as_ref().into(),
             mappings: HashMap::new(),
             links: HashMap::new(),
+            blocks: HashMap::new(),
+            extents: HashMap::new(),
+            localization: self.localization,
             inner: Passthrough::options()
                 .debug(self.debug)
                 .foreground(self.foreground)
                 .build::<&str>(datadir.as_ref().into()),
             allocator: A::default(),
+            checkpointer: Checkpointer::new(self.checkpoint),
+            journal: None,
+            check: self.check,
+            repair: self.repair,
+            degree: self.degree,
+            lock_memory: self.lock_memory,
+            relocked: false,
         })
     }
 }
@@ -1,8 +1,35 @@
-use super::Key;
+use crate::{localizer::Localizer, Key};
 use crypter::Crypter;
 use kms::KeyManagementScheme;
 use rand::{CryptoRng, RngCore};
 use sdbtree::{error::Error, storage::Storage, BKeyTree};
+use std::{collections::BTreeMap, ops::Range};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// Maps the indices of a file's extents to the extents allocated for them.
+///
+/// Extents are allocated from the same allocator as inode IDs, so no two files (or extents of the
+/// same file) ever share keys.
+pub(crate) type Extents = BTreeMap<u64, u64>;
+
+/// The extents of a file that doesn't have any yet.
+pub(crate) static NO_EXTENTS: Extents = Extents::new();
+
+#[derive(Debug, Error)]
+pub enum LocalizeError<E> {
+    #[error("no extent allocated for block {0}")]
+    Unmapped(u64),
+
+    #[error("key tree error")]
+    Tree(Error<E>),
+}
+
+impl<E> From<Error<E>> for LocalizeError<E> {
+    fn from(err: Error<E>) -> Self {
+        Self::Tree(err)
+    }
+}
 
 pub struct LocalizedBKeyTree<'a, R, S, C, const KEY_SZ: usize>
 where
@@ -10,8 +37,8 @@ where
     S: Storage<Id = u64>,
     C: Crypter,
 {
-    id: u64,
-    localizer: fn(u64, u64) -> u64,
+    localizer: &'a dyn Localizer,
+    extents: &'a Extents,
     inner: &'a mut BKeyTree<R, S, C, KEY_SZ>,
 }
 
@@ -22,16 +49,76 @@ where
     C: Crypter,
 {
     pub fn new(
-        id: u64,
-        localizer: fn(u64, u64) -> u64,
+        localizer: &'a dyn Localizer,
+        extents: &'a Extents,
         inner: &'a mut BKeyTree<R, S, C, KEY_SZ>,
     ) -> Self {
         Self {
-            id,
             localizer,
+            extents,
             inner,
         }
     }
+
+    fn localize(&self, block: u64) -> Result<u64, LocalizeError<S::Error>> {
+        let extent = self
+            .extents
+            .get(&self.localizer.extent_index(block))
+            .ok_or(LocalizeError::Unmapped(block))?;
+        Ok(self
+            .localizer
+            .localize(*extent, block & (self.localizer.extent_blocks() - 1)))
+    }
+
+    /// Splits a range of blocks at extent boundaries, returning each allocated extent along with
+    /// the blocks of the range that fall in it.
+    fn extent_ranges(&self, blocks: Range<u64>) -> Vec<(u64, Range<u64>)> {
+        if blocks.is_empty() {
+            return Vec::new();
+        }
+
+        let extent_blocks = self.localizer.extent_blocks();
+        let first = self.localizer.extent_index(blocks.start);
+        let last = self.localizer.extent_index(blocks.end - 1);
+        self.extents
+            .range(first..=last)
+            .map(|(&index, &extent)| {
+                let base = index * extent_blocks;
+                (
+                    extent,
+                    blocks.start.max(base)..blocks.end.min(base + extent_blocks),
+                )
+            })
+            .collect()
+    }
+
+    /// Removes the keys for every block in `blocks` that falls in an allocated extent, returning
+    /// how many existed.
+    pub fn remove_range(&mut self, blocks: Range<u64>) -> Result<usize, LocalizeError<S::Error>> {
+        let mask = self.localizer.extent_blocks() - 1;
+        let mut removed = 0;
+        for (extent, range) in self.extent_ranges(blocks) {
+            for block in range {
+                let key = self.localizer.localize(extent, block & mask);
+                removed += self.inner.remove(&key)?.is_some() as usize;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Persists the nodes containing the keys for every block in `blocks` that falls in an
+    /// allocated extent, returning how many keys there were.
+    pub fn persist_range(&mut self, blocks: Range<u64>) -> Result<usize, LocalizeError<S::Error>> {
+        let mask = self.localizer.extent_blocks() - 1;
+        let mut persisted = 0;
+        for (extent, range) in self.extent_ranges(blocks) {
+            for block in range {
+                let key = self.localizer.localize(extent, block & mask);
+                persisted += self.inner.persist_block(&key)? as usize;
+            }
+        }
+        Ok(persisted)
+    }
 }
 
 impl<'a, R, S, C, const KEY_SZ: usize> KeyManagementScheme
@@ -41,16 +128,19 @@ where
     S: Storage<Id = u64>,
     C: Crypter,
 {
-    type Key = Key<KEY_SZ>;
+    // Keys are wiped once the crypt I/O is done with them.
+    type Key = Zeroizing<Key<KEY_SZ>>;
     type KeyId = u64;
-    type Error = Error<S::Error>;
+    type Error = LocalizeError<S::Error>;
 
     fn derive(&mut self, block: Self::KeyId) -> Result<Self::Key, Self::Error> {
-        self.inner.derive((self.localizer)(self.id, block))
+        let key = self.localize(block)?;
+        Ok(Zeroizing::new(self.inner.derive(key)?))
     }
 
     fn update(&mut self, block: Self::KeyId) -> Result<Self::Key, Self::Error> {
-        self.inner.update((self.localizer)(self.id, block))
+        let key = self.localize(block)?;
+        Ok(Zeroizing::new(self.inner.update(key)?))
     }
 
     fn commit(&mut self) -> Vec<Self::KeyId> {
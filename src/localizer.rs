@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// Maps a block's position within one of a file's extents to the ID of its key in the tree.
+///
+/// Every strategy has to be a bijection, so no two blocks ever share a key.
+pub trait Localizer {
+    /// Bits of a key taken up by a block's offset within its extent.
+    fn extent_bits(&self) -> u32;
+
+    fn localize(&self, extent: u64, offset: u64) -> u64;
+
+    /// Recovers the extent and offset that a key was localized from.
+    fn unlocalize(&self, key: u64) -> (u64, u64);
+
+    /// The extent index that a block falls in.
+    fn extent_index(&self, block: u64) -> u64 {
+        block >> self.extent_bits()
+    }
+
+    /// The number of blocks whose keys fit in an extent.
+    fn extent_blocks(&self) -> u64 {
+        1 << self.extent_bits()
+    }
+
+    /// The largest extent ID that still fits in a key.
+    fn max_extent(&self) -> u64 {
+        u64::MAX >> self.extent_bits()
+    }
+}
+
+/// Packs the extent into the high bits of the key and the offset into the low bits.
+pub struct Packed;
+
+impl Localizer for Packed {
+    fn extent_bits(&self) -> u32 {
+        20
+    }
+
+    fn localize(&self, extent: u64, offset: u64) -> u64 {
+        extent << self.extent_bits() | offset
+    }
+
+    fn unlocalize(&self, key: u64) -> (u64, u64) {
+        (key >> self.extent_bits(), key & (self.extent_blocks() - 1))
+    }
+}
+
+/// Packs keys like [`Packed`], then scatters them across the key space with an invertible hash so
+/// neighbouring blocks land in different tree nodes.
+///
+/// Persists and deletions of a file's keys touch more nodes than with [`Packed`], but a key's
+/// node holds few keys of the same file.
+pub struct Interleaved;
+
+impl Interleaved {
+    const M1: u64 = 0xbf58476d1ce4e5b9;
+    const M2: u64 = 0x94d049bb133111eb;
+
+    /// The inverse of an odd number modulo 2^64, by Newton's method.
+    const fn inverse(m: u64) -> u64 {
+        let mut inv = m;
+        let mut i = 0;
+        while i < 5 {
+            inv = inv.wrapping_mul(2u64.wrapping_sub(m.wrapping_mul(inv)));
+            i += 1;
+        }
+        inv
+    }
+
+    // Shifting by half the width makes each xorshift its own inverse.
+    fn mix(mut x: u64) -> u64 {
+        x ^= x >> 32;
+        x = x.wrapping_mul(Self::M1);
+        x ^= x >> 32;
+        x = x.wrapping_mul(Self::M2);
+        x ^ x >> 32
+    }
+
+    fn unmix(mut x: u64) -> u64 {
+        x ^= x >> 32;
+        x = x.wrapping_mul(Self::inverse(Self::M2));
+        x ^= x >> 32;
+        x = x.wrapping_mul(Self::inverse(Self::M1));
+        x ^ x >> 32
+    }
+}
+
+impl Localizer for Interleaved {
+    fn extent_bits(&self) -> u32 {
+        Packed.extent_bits()
+    }
+
+    fn localize(&self, extent: u64, offset: u64) -> u64 {
+        Self::mix(Packed.localize(extent, offset))
+    }
+
+    fn unlocalize(&self, key: u64) -> (u64, u64) {
+        Packed.unlocalize(Self::unmix(key))
+    }
+}
+
+/// Packs keys like [`Packed`], but with small extents, so the keys of small files sit next to
+/// each other and share tree nodes, and deleting a file only touches the extents it used.
+pub struct Contiguous;
+
+impl Localizer for Contiguous {
+    fn extent_bits(&self) -> u32 {
+        8
+    }
+
+    fn localize(&self, extent: u64, offset: u64) -> u64 {
+        extent << self.extent_bits() | offset
+    }
+
+    fn unlocalize(&self, key: u64) -> (u64, u64) {
+        (key >> self.extent_bits(), key & (self.extent_blocks() - 1))
+    }
+}
+
+/// The key localization strategy a volume was created with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Localization {
+    #[default]
+    Packed,
+    Interleaved,
+    Contiguous,
+}
+
+impl Localization {
+    pub fn localizer(self) -> &'static dyn Localizer {
+        match self {
+            Self::Packed => &Packed,
+            Self::Interleaved => &Interleaved,
+            Self::Contiguous => &Contiguous,
+        }
+    }
+}
+
+impl fmt::Display for Localization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Packed => "packed",
+            Self::Interleaved => "interleaved",
+            Self::Contiguous => "contiguous",
+        })
+    }
+}
+
+impl FromStr for Localization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "packed" => Ok(Self::Packed),
+            "interleaved" => Ok(Self::Interleaved),
+            "contiguous" => Ok(Self::Contiguous),
+            _ => Err(format!(
+                "unknown localization {s:?} (expected packed, interleaved, or contiguous)"
+            )),
+        }
+    }
+}
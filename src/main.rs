@@ -1,8 +1,16 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{anyhow, bail, Result};
+use clap::{Parser, Subcommand};
+use rand::rngs::OsRng;
 use sdbtree::storage::dir::DirectoryStorage;
-use sdbtreefs::SDBTreeFs;
-use std::fs;
+use sdbtreefs::{
+    checkpoint::CheckpointPolicy,
+    enclave::{AgentEnclave, Enclave, FileEnclave, Keyring, KeyringEnclave, Root},
+    localizer::Localization,
+    shamir::{self, Share},
+    utils, SDBTreeFs, AES256CTR_KEY_SZ,
+};
+use std::{fs, io::Write, os::unix::fs::OpenOptionsExt, path::Path, time::Duration};
+use zeroize::Zeroizing;
 
 #[derive(Parser)]
 struct Args {
@@ -18,13 +26,86 @@ struct Args {
     #[clap(short = 't', long, default_value = "/tmp/sdbtreefsmeta")]
     metadir: String,
 
-    /// The enclave to store Lethe's master key in
+    /// The enclave to store Lethe's master key in, either a file or a zeroed block device
     #[clap(short, long, default_value = "/tmp/sdbtreefsenclave")]
     enclave: String,
 
-    /// The degree to use for the BTree
-    #[clap(short = 'n', long, default_value_t = 2)]
-    degree: usize,
+    /// Keep Lethe's master key in this kernel keyring instead of a file: thread, process,
+    /// session, user, or user-session
+    #[clap(long, conflicts_with_all = ["agent", "passphrase", "passphrase_file", "key_file"])]
+    keyring: Option<Keyring>,
+
+    /// The description of the master key's entry in the keyring [default: sdbtreefs:<metadir>]
+    #[clap(long, requires = "keyring")]
+    key_description: Option<String>,
+
+    /// Ask the sdbtreefs-agent listening on this socket for Lethe's master key instead of a file,
+    /// naming the volume by its metadir
+    #[clap(long, conflicts_with_all = ["passphrase", "passphrase_file", "key_file"])]
+    agent: Option<String>,
+
+    /// The user the agent has to be running as, besides root [default: our own]
+    #[clap(long, requires = "agent")]
+    agent_uid: Option<u32>,
+
+    /// Prompt for a passphrase to protect the enclave with
+    #[clap(
+        short,
+        long,
+        default_value_t = false,
+        conflicts_with = "passphrase_file"
+    )]
+    passphrase: bool,
+
+    /// Read the passphrase to protect the enclave with from a file
+    #[clap(long, conflicts_with = "key_file")]
+    passphrase_file: Option<String>,
+
+    /// Protect the enclave with the contents of a keyfile
+    #[clap(long, conflicts_with = "passphrase")]
+    key_file: Option<String>,
+
+    /// Keep the enclave on a copy-on-write filesystem, where replaced keys can't be destroyed
+    #[clap(long, default_value_t = false)]
+    allow_insecure_enclave: bool,
+
+    /// The degree to use for the BTree [default: sdbtree's own]
+    #[clap(short = 'n', long)]
+    degree: Option<usize>,
+
+    /// Checkpoint state every this many seconds
+    #[clap(
+        long,
+        conflicts_with = "checkpoint_ops",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    checkpoint_secs: Option<u64>,
+
+    /// Checkpoint state every this many mutating operations
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    checkpoint_ops: Option<u64>,
+
+    /// How to place keys in the BTree: packed, interleaved, or contiguous
+    #[clap(long, default_value_t = Localization::Packed)]
+    localization: Localization,
+
+    /// Check the datadir against the metadata on load, which otherwise only happens after an
+    /// unclean unmount
+    #[clap(long, default_value_t = false)]
+    check: bool,
+
+    /// Repair inconsistencies found between the datadir and metadata on load
+    #[clap(long, default_value_t = false)]
+    repair: bool,
+
+    /// Lock all memory so keys can't be swapped out, and disable core dumps
+    #[clap(long, default_value_t = false)]
+    lock_memory: bool,
+
+    /// Restore the root key from a threshold of shares made by split-key into an empty enclave
+    /// before mounting
+    #[clap(long, num_args = 1.., value_name = "SHARE")]
+    restore_from_shares: Vec<String>,
 
     /// Run filesystem in debug mode
     #[clap(short = 'v', long, default_value_t = false)]
@@ -33,26 +114,288 @@ struct Args {
     /// Run filesystem in foreground
     #[clap(short, long, default_value_t = false)]
     foreground: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the passphrases and keyfiles that can unlock the enclave
+    Keyslot {
+        #[clap(subcommand)]
+        action: KeyslotAction,
+    },
+
+    /// Split the enclave's current root key into shares, any threshold of which can restore it
+    ///
+    /// The root key changes with every commit, so the shares only restore the volume as of now,
+    /// together with a backup of the metadir taken while it's unmounted.
+    SplitKey {
+        /// How many shares it takes to restore the root key
+        #[clap(long)]
+        threshold: u8,
+
+        /// How many shares to make
+        #[clap(long)]
+        shares: u8,
+
+        /// The directory to write the shares to
+        #[clap(long)]
+        out_dir: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeyslotAction {
+    /// Add a keyslot for another passphrase or keyfile
+    Add {
+        /// Read the new passphrase from a file instead of prompting for it
+        #[clap(long, conflicts_with = "new_key_file")]
+        new_passphrase_file: Option<String>,
+
+        /// Use the contents of a keyfile as the new credential
+        #[clap(long)]
+        new_key_file: Option<String>,
+    },
+
+    /// Securely overwrite a keyslot so it can't unlock the enclave anymore
+    Remove { index: usize },
+
+    /// List the keyslots in use
+    List,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
 
+    pretty_env_logger::init();
+
+    // This has to come before anything secret is read to cover it. A mount takes the locks
+    // again once it's in the background.
+    if args.lock_memory {
+        utils::lock_memory()?;
+    }
+
+    match &args.command {
+        Some(Command::Keyslot { action }) => return keyslot(&args, action),
+        Some(Command::SplitKey {
+            threshold,
+            shares,
+            out_dir,
+        }) => return split_key(&args, *threshold, *shares, out_dir),
+        None => {}
+    }
+
     let _ = fs::create_dir_all(&args.mount);
     let _ = fs::create_dir_all(&args.datadir);
     let _ = fs::create_dir_all(&args.metadir);
 
-    pretty_env_logger::init();
+    // Without an explicit policy, we only checkpoint on fsync and unmount.
+    let checkpoint = match (args.checkpoint_secs, args.checkpoint_ops) {
+        (Some(secs), _) => CheckpointPolicy::Interval(Duration::from_secs(secs)),
+        (_, Some(ops)) => CheckpointPolicy::Operations(ops),
+        _ => CheckpointPolicy::OnSync,
+    };
+
+    let mut enclave = open_enclave(&args)?;
+    if !args.restore_from_shares.is_empty() {
+        restore(enclave.as_mut(), &args.restore_from_shares)?;
+    }
+
+    mount(&args, checkpoint, enclave)
+}
+
+fn open_enclave(args: &Args) -> Result<Box<dyn Enclave<AES256CTR_KEY_SZ>>> {
+    Ok(if let Some(keyring) = args.keyring {
+        let description = match &args.key_description {
+            Some(description) => description.clone(),
+            None => format!("sdbtreefs:{}", args.metadir),
+        };
+        Box::new(KeyringEnclave::new(keyring, &description)?)
+    } else if let Some(socket) = &args.agent {
+        let mut enclave = AgentEnclave::new(socket, &args.metadir);
+        if let Some(uid) = args.agent_uid {
+            enclave = enclave.agent_uid(uid);
+        }
+        Box::new(enclave)
+    } else {
+        let mut options = FileEnclave::options().allow_insecure(args.allow_insecure_enclave);
+        if let Some(credential) = credential(args)? {
+            options = options.passphrase(&credential);
+        }
+        Box::new(options.open(&args.enclave)?)
+    })
+}
+
+fn mount(
+    args: &Args,
+    checkpoint: CheckpointPolicy,
+    enclave: Box<dyn Enclave<AES256CTR_KEY_SZ>>,
+) -> Result<()> {
+    // libfuse changes to `/` when it forks into the background, so relative paths would stop
+    // pointing at the volume.
+    let absolute = |path: &str| -> Result<String> {
+        Ok(fs::canonicalize(path)?.to_string_lossy().into_owned())
+    };
+    let datadir = absolute(&args.datadir)?;
+    let metadir = absolute(&args.metadir)?;
 
     SDBTreeFs::options()
         .debug(args.debug)
         .foreground(args.foreground)
         .degree(args.degree)
+        .checkpoint(checkpoint)
+        .check(args.check)
+        .repair(args.repair)
+        .lock_memory(args.lock_memory)
+        .localization(args.localization)
         .build(
-            &args.enclave,
-            &args.datadir,
-            &args.metadir,
-            DirectoryStorage::new(&args.metadir)?,
+            enclave,
+            &datadir,
+            &metadir,
+            DirectoryStorage::new(&metadir)?,
         )?
-        .mount(args.mount)
+        .mount(&args.mount)
+}
+
+/// Reads the credential that unlocks the enclave, if one was given.
+fn credential(args: &Args) -> Result<Option<Zeroizing<Vec<u8>>>> {
+    Ok(if args.passphrase {
+        let passphrase = prompt("Enclave passphrase: ")?;
+
+        // A new enclave gets wrapped under whatever was typed, so a typo would lock it for good.
+        if FileEnclave::is_new(&args.enclave)?
+            && *passphrase != *prompt("Confirm enclave passphrase: ")?
+        {
+            bail!("passphrases don't match");
+        }
+        Some(passphrase)
+    } else if let Some(path) = &args.passphrase_file {
+        Some(read_passphrase_file(path)?)
+    } else if let Some(path) = &args.key_file {
+        Some(Zeroizing::new(fs::read(path)?))
+    } else {
+        None
+    })
+}
+
+fn prompt(prompt: &str) -> Result<Zeroizing<Vec<u8>>> {
+    Ok(Zeroizing::new(
+        rpassword::prompt_password(prompt)?.into_bytes(),
+    ))
+}
+
+/// Only a single trailing newline is stripped from passphrase files, since anything else could be
+/// part of the passphrase.
+fn read_passphrase_file(path: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let passphrase = Zeroizing::new(fs::read_to_string(path)?);
+    let passphrase = match passphrase.strip_suffix('\n') {
+        Some(passphrase) => passphrase.strip_suffix('\r').unwrap_or(passphrase),
+        None => passphrase.as_str(),
+    };
+    Ok(Zeroizing::new(passphrase.as_bytes().to_vec()))
+}
+
+fn keyslot(args: &Args, action: &KeyslotAction) -> Result<()> {
+    if args.keyring.is_some() || args.agent.is_some() {
+        bail!("keyslots only exist in file enclaves");
+    }
+
+    // Opening an enclave creates it, so a mistyped path would otherwise get a new one.
+    if FileEnclave::is_new(&args.enclave)? {
+        bail!("there's no enclave at {}", args.enclave);
+    }
+
+    // Keyslots can only be managed once the enclave is unlocked.
+    let credential = match credential(args)? {
+        Some(credential) => credential,
+        None => prompt("Enclave passphrase: ")?,
+    };
+    let mut enclave = FileEnclave::options()
+        .passphrase(&credential)
+        .allow_insecure(args.allow_insecure_enclave)
+        .open(&args.enclave)?;
+
+    match action {
+        KeyslotAction::Add {
+            new_passphrase_file,
+            new_key_file,
+        } => {
+            let new = if let Some(path) = new_passphrase_file {
+                read_passphrase_file(path)?
+            } else if let Some(path) = new_key_file {
+                Zeroizing::new(fs::read(path)?)
+            } else {
+                let new = prompt("New passphrase: ")?;
+                if *new != *prompt("Confirm new passphrase: ")? {
+                    bail!("passphrases don't match");
+                }
+                new
+            };
+            println!("added keyslot {}", enclave.add_keyslot(&new)?);
+        }
+        KeyslotAction::Remove { index } => {
+            enclave.remove_keyslot(*index)?;
+            println!("removed keyslot {index}");
+        }
+        KeyslotAction::List => {
+            for keyslot in enclave.keyslots()? {
+                println!(
+                    "keyslot {}: argon2id m={} t={} p={}",
+                    keyslot.index, keyslot.m_cost, keyslot.t_cost, keyslot.p_cost
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn split_key(args: &Args, threshold: u8, shares: u8, out_dir: &str) -> Result<()> {
+    // Splitting only reads the enclave, `get` would also wipe the slot that isn't committed.
+    let root = open_enclave(args)?
+        .peek()?
+        .ok_or_else(|| anyhow!("the enclave doesn't hold a root key"))?;
+    let shares = shamir::split(&root.encode(), threshold, shares, &mut OsRng)?;
+
+    fs::create_dir_all(out_dir)?;
+    for share in &shares {
+        let path = Path::new(out_dir).join(format!("share-{}", share.index()));
+        fs::File::options()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?
+            .write_all(&share.to_bytes()?)?;
+        println!("wrote {}", path.display());
+    }
+
+    println!(
+        "any {threshold} of the {} shares restore epoch {}",
+        shares.len(),
+        root.epoch
+    );
+    Ok(())
+}
+
+/// Seeds an empty enclave with the root key reconstructed from shares, so the volume loads from it
+/// like any other.
+fn restore(enclave: &mut dyn Enclave<AES256CTR_KEY_SZ>, paths: &[String]) -> Result<()> {
+    if enclave.is_initialized()? {
+        bail!(
+            "the enclave already holds a root key, shares can only be restored into an empty one"
+        );
+    }
+
+    let shares = paths
+        .iter()
+        .map(|path| Ok(Share::from_bytes(&fs::read(path)?)?))
+        .collect::<Result<Vec<_>>>()?;
+    let root = Root::<AES256CTR_KEY_SZ>::decode(&shamir::combine(&shares)?)
+        .ok_or_else(|| anyhow!("the shares don't reconstruct a valid root key"))?;
+
+    enclave.replace(&root)?;
+    println!("restored the root key for epoch {}", root.epoch);
+    Ok(())
 }
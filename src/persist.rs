@@ -1,92 +1,377 @@
-use crate::{error::Error, SDBResult, SDBTreeFs};
+use crate::{
+    enclave::{Enclave, Root},
+    error::Error,
+    journal::Journal,
+    seal::Sealer,
+    CipherName, SDBResult, SDBTreeFs,
+};
 use allocator::Allocator;
 use crypter::Crypter;
 use embedded_io::{
     adapters::FromStd,
-    blocking::{Read, Seek, Write},
-    SeekFrom,
+    blocking::{Read, Write},
 };
-use rand::{CryptoRng, RngCore};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use log::debug;
+use rand::{rngs::OsRng, CryptoRng, RngCore};
 use sdbtree::storage::Storage;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::fs::File;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    path::Path,
+};
+use zeroize::Zeroizing;
+
+const MANIFEST_KEY_INFO: &[u8] = b"sdbtreefs manifest";
+const METADATA_KEY_INFO: &[u8] = b"sdbtreefs metadata";
+
+/// Describes a single committed epoch of metadata.
+///
+/// The manifest is the only thing `load()` trusts to find the rest of the metadata, so a
+/// persist that crashes before the manifest is committed leaves the previous epoch intact. It is
+/// MAC'd under a key derived from the epoch's root key, and the epoch must match the one stored
+/// alongside the root key in the enclave, so a rolled-back metadir can't pass for a current one.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub epoch: u64,
+    pub root_id: u64,
+    /// SHA-256 digests of the links, mappings, allocator, blocks, and extents files.
+    pub digests: Vec<[u8; 32]>,
+    pub mac: [u8; 32],
+}
+
+impl Manifest {
+    pub fn new(root_key: &[u8], epoch: u64, root_id: u64, digests: Vec<[u8; 32]>) -> Self {
+        let mut manifest = Self {
+            epoch,
+            root_id,
+            digests,
+            mac: [0; 32],
+        };
+        manifest.mac = manifest.hmac(root_key).finalize().into_bytes().into();
+        manifest
+    }
+
+    pub fn verify(&self, root_key: &[u8]) -> bool {
+        self.hmac(root_key).verify_slice(&self.mac).is_ok()
+    }
+
+    fn hmac(&self, root_key: &[u8]) -> Hmac<Sha256> {
+        let mut key = Zeroizing::new([0; 32]);
+        Hkdf::<Sha256>::new(None, root_key)
+            .expand(MANIFEST_KEY_INFO, key.as_mut_slice())
+            .unwrap();
+
+        let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(key.as_slice()).unwrap();
+        hmac.update(&self.epoch.to_le_bytes());
+        hmac.update(&self.root_id.to_le_bytes());
+        for digest in &self.digests {
+            hmac.update(digest);
+        }
+        hmac
+    }
+}
+
+/// Encrypts an epoch's metadata under a key derived from its root key.
+///
+/// Sealed blobs are bound to their epoch and to what they hold, so the namespace stays hidden and
+/// one blob can't stand in for another.
+pub(crate) struct MetadataCipher {
+    sealer: Sealer,
+    epoch: u64,
+}
+
+impl MetadataCipher {
+    pub fn new(root_key: &[u8], epoch: u64) -> Self {
+        Self {
+            sealer: Sealer::derive(root_key, METADATA_KEY_INFO),
+            epoch,
+        }
+    }
+
+    pub fn seal(&self, name: &str, plain: &[u8]) -> Vec<u8> {
+        self.sealer.seal(plain, &self.aad(name), &mut OsRng)
+    }
+
+    /// Decrypts a sealed blob, returning `None` if it's been tampered with or belongs elsewhere.
+    pub fn open(&self, name: &str, sealed: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+        self.sealer.open(sealed, &self.aad(name))
+    }
 
-impl<A, R, S, C, const KEY_SZ: usize, const BLOCK_SZ: usize> SDBTreeFs<A, R, S, C, KEY_SZ, BLOCK_SZ>
+    fn aad(&self, name: &str) -> Vec<u8> {
+        [self.epoch.to_le_bytes().as_slice(), name.as_bytes()].concat()
+    }
+}
+
+impl<A, R, S, C, E, const KEY_SZ: usize, const BLOCK_SZ: usize>
+    SDBTreeFs<A, R, S, C, E, KEY_SZ, BLOCK_SZ>
 where
     for<'de> A: Allocator<Id = u64> + Default + Serialize + Deserialize<'de> + 'static,
     R: RngCore + CryptoRng + Default + 'static,
     S: Storage<Id = u64> + 'static,
-    C: Crypter + 'static,
+    C: Crypter + CipherName + 'static,
+    E: Enclave<KEY_SZ> + 'static,
 {
-    pub(crate) fn allocator_path(&self) -> String {
-        format!("{}/allocator", self.metadir)
+    pub(crate) fn allocator_path(&self, epoch: u64) -> String {
+        format!("{}/allocator.{epoch}", self.metadir)
+    }
+
+    pub(crate) fn links_path(&self, epoch: u64) -> String {
+        format!("{}/links.{epoch}", self.metadir)
+    }
+
+    pub(crate) fn mappings_path(&self, epoch: u64) -> String {
+        format!("{}/mappings.{epoch}", self.metadir)
+    }
+
+    pub(crate) fn blocks_path(&self, epoch: u64) -> String {
+        format!("{}/blocks.{epoch}", self.metadir)
+    }
+
+    pub(crate) fn extents_path(&self, epoch: u64) -> String {
+        format!("{}/extents.{epoch}", self.metadir)
     }
 
-    pub(crate) fn links_path(&self) -> String {
-        format!("{}/links", self.metadir)
+    pub(crate) fn journal_path(&self, epoch: u64) -> String {
+        format!("{}/journal.{epoch}", self.metadir)
     }
 
-    pub(crate) fn mappings_path(&self) -> String {
-        format!("{}/mappings", self.metadir)
+    /// Only exists while the volume isn't mounted, and only if it was last unmounted cleanly.
+    fn clean_path(&self) -> String {
+        format!("{}/clean", self.metadir)
     }
 
-    pub(crate) fn root_path(&self) -> String {
-        format!("{}/root", self.metadir)
+    /// Marks the volume as cleanly unmounted, once everything has been persisted.
+    pub(crate) fn mark_clean(&self) -> SDBResult<()> {
+        File::create(self.clean_path())?;
+        self.sync_metadir()
+    }
+
+    /// Manifests alternate between slots along with the enclave's root records.
+    pub(crate) fn manifest_path(&self, epoch: u64) -> String {
+        format!("{}/manifest.{}", self.metadir, Root::<KEY_SZ>::index(epoch))
     }
 
     pub fn is_loadable(&mut self) -> SDBResult<bool> {
         // If a key is in the enclave, we should have persisted state that we can load.
-        self.enclave.seek(SeekFrom::End(0))?;
-        Ok(self.enclave.stream_position()? != 0)
+        self.enclave.is_initialized()
     }
 
     pub fn load(&mut self) -> SDBResult<()> {
-        // Load the root key from the enclave.
-        let mut root_key = [0; KEY_SZ];
-        self.enclave.seek(SeekFrom::Start(0))?;
-        self.enclave
-            .read_exact(&mut root_key)
-            .map_err(|_| Error::Enclave)?;
-
-        // Load the public state: links, mappings, allocator, and root ID.
-        let links = Self::load_serializable(&self.links_path())?;
-        let mappings = Self::load_serializable(&self.mappings_path())?;
-        let allocator = Self::load_serializable(&self.allocator_path())?;
-        let root_id = Self::load_serializable(&self.root_path())?;
+        // Make sure the volume is one we can actually load, upgrading it if it's older.
+        self.check_superblock()?;
+
+        // Load the root key and epoch of the committed epoch.
+        let Root { epoch, root_key } = self.enclave.get()?.ok_or(Error::Enclave)?;
+
+        // The manifest for that epoch was made durable before the enclave write that committed
+        // it, so anything else means the metadir was rolled back or tampered with.
+        let manifest = self
+            .load_manifest(&self.manifest_path(epoch))?
+            .ok_or(Error::Manifest)?;
+
+        if manifest.epoch < epoch {
+            return Err(Error::Rollback {
+                enclave: epoch,
+                metadir: manifest.epoch,
+            });
+        }
+        if manifest.epoch != epoch || !manifest.verify(root_key.as_slice()) {
+            return Err(Error::Manifest);
+        }
+
+        // Load the sealed state: links, mappings, allocator, blocks, and extents.
+        let &[links_digest, mappings_digest, allocator_digest, blocks_digest, extents_digest] =
+            manifest.digests.as_slice()
+        else {
+            return Err(Error::Manifest);
+        };
+        let metadata = MetadataCipher::new(root_key.as_slice(), epoch);
+        let links = Self::load_sealed(&self.links_path(epoch), "links", &links_digest, &metadata)?;
+        let mappings = Self::load_sealed(
+            &self.mappings_path(epoch),
+            "mappings",
+            &mappings_digest,
+            &metadata,
+        )?;
+        let allocator = Self::load_sealed(
+            &self.allocator_path(epoch),
+            "allocator",
+            &allocator_digest,
+            &metadata,
+        )?;
+        let blocks = Self::load_sealed(
+            &self.blocks_path(epoch),
+            "blocks",
+            &blocks_digest,
+            &metadata,
+        )?;
+        let extents = Self::load_sealed(
+            &self.extents_path(epoch),
+            "extents",
+            &extents_digest,
+            &metadata,
+        )?;
 
         // Load the BTree.
         self.tree
-            .load(root_id, root_key)
+            .load(manifest.root_id, *root_key)
             .map_err(|_| Error::Storage)?;
 
         // We can go ahead and update the rest of the state.
         self.links = links;
         self.mappings = mappings;
         self.allocator = allocator;
-        self.root_id = root_id;
-        self.root_key = self.root_key;
+        self.blocks = blocks;
+        self.extents = extents;
+        self.epoch = manifest.epoch;
+        self.root_id = manifest.root_id;
+        *self.root_key = *root_key;
+
+        // Anything left over from the previous epoch is garbage now.
+        self.remove_epoch(manifest.epoch.wrapping_sub(1));
+
+        // Replay namespace changes made since the epoch was committed, then commit them so the
+        // journal starts out empty again.
+        let (journal, ops) = Journal::open(
+            &self.journal_path(self.epoch),
+            self.root_key.as_slice(),
+            self.epoch,
+        )?;
+        self.journal = Some(journal);
+
+        if !ops.is_empty() {
+            debug!("replaying {} journaled operations", ops.len());
+            for op in &ops {
+                self.apply(op)?;
+            }
+        }
+
+        // The mark only counts for the unmount that left it, so it's gone while we're mounted.
+        let clean = Path::new(&self.clean_path()).exists();
+        if clean {
+            fs::remove_file(self.clean_path())?;
+            self.sync_metadir()?;
+        }
+
+        // Checking the loaded state against what's actually in the datadir walks the whole
+        // datadir, so it's only done when asked to or when the last mount didn't end cleanly.
+        let mut repaired = false;
+        if self.check || self.repair || !clean || !ops.is_empty() {
+            let report = self.reconcile(self.repair)?;
+            report.log();
+            repaired = self.repair && !report.is_clean();
+        }
+
+        if !ops.is_empty() || repaired {
+            self.persist()?;
+        }
 
         Ok(())
     }
 
     pub fn persist(&mut self) -> SDBResult<()> {
         // Persist the BTree, which will give us the next root ID and root key.
-        (self.root_id, self.root_key) = self.tree.persist().map_err(|_| Error::Storage)?;
+        let (root_id, root_key) = self.tree.persist().map_err(|_| Error::Storage)?;
+        let root_key = Zeroizing::new(root_key);
+        let epoch = self.epoch + 1;
 
-        // Persist the public state: links, mappings, allocator, and root ID.
-        Self::persist_serializable(&self.links_path(), &self.links)?;
-        Self::persist_serializable(&self.mappings_path(), &self.mappings)?;
-        Self::persist_serializable(&self.allocator_path(), &self.allocator)?;
-        Self::persist_serializable(&self.root_path(), &self.root_id)?;
+        // Persist the rest of the state for the new epoch alongside the current one, sealed under
+        // the new root key.
+        let metadata = MetadataCipher::new(root_key.as_slice(), epoch);
+        let digests = vec![
+            Self::persist_sealed(&self.links_path(epoch), "links", &self.links, &metadata)?,
+            Self::persist_sealed(
+                &self.mappings_path(epoch),
+                "mappings",
+                &self.mappings,
+                &metadata,
+            )?,
+            Self::persist_sealed(
+                &self.allocator_path(epoch),
+                "allocator",
+                &self.allocator,
+                &metadata,
+            )?,
+            Self::persist_sealed(&self.blocks_path(epoch), "blocks", &self.blocks, &metadata)?,
+            Self::persist_sealed(
+                &self.extents_path(epoch),
+                "extents",
+                &self.extents,
+                &metadata,
+            )?,
+        ];
+
+        // Stage the manifest for the new epoch in the manifest slot that isn't in use. It only
+        // takes effect once the enclave holds the matching root key and epoch.
+        let manifest = Manifest::new(root_key.as_slice(), epoch, root_id, digests);
+        Self::persist_serializable(&self.manifest_path(epoch), &manifest)?;
+        self.sync_metadir()?;
+
+        // Replace the root key and epoch in the enclave. This is the commit point.
+        self.enclave.replace(&Root {
+            epoch,
+            root_key: root_key.clone(),
+        })?;
+
+        // Now that the new record is durable, start a fresh journal and clean up the previous
+        // epoch.
+        self.journal = Some(Journal::create(
+            &self.journal_path(epoch),
+            root_key.as_slice(),
+            epoch,
+        )?);
+        self.remove_epoch(self.epoch);
+
+        self.epoch = epoch;
+        self.root_id = root_id;
+        *self.root_key = *root_key;
+
+        self.checkpointer.reset();
+
+        Ok(())
+    }
 
-        // Persist the root key to the enclave.
-        self.enclave.seek(SeekFrom::Start(0))?;
-        self.enclave.write_all(&self.root_key)?;
+    /// Persists state if the checkpoint policy says a checkpoint is due.
+    pub(crate) fn checkpoint(&mut self, mutated: bool) -> SDBResult<()> {
+        // Every request comes through here, so it's where the first one takes back the memory
+        // locks that libfuse dropped when it forked into the background.
+        if !self.relocked {
+            self.relock();
+        }
 
+        if self.checkpointer.record(mutated) {
+            debug!("checkpoint");
+            self.persist()?;
+        }
         Ok(())
     }
 
-    fn load_serializable<T: DeserializeOwned>(path: &str) -> SDBResult<T> {
+    fn load_manifest(&self, path: &str) -> SDBResult<Option<Manifest>> {
+        match Self::load_serializable(path) {
+            Ok(manifest) => Ok(Some(manifest)),
+            Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn remove_epoch(&self, epoch: u64) {
+        // Missing files are fine, they were either never written or already cleaned up.
+        let _ = fs::remove_file(self.links_path(epoch));
+        let _ = fs::remove_file(self.mappings_path(epoch));
+        let _ = fs::remove_file(self.allocator_path(epoch));
+        let _ = fs::remove_file(self.blocks_path(epoch));
+        let _ = fs::remove_file(self.extents_path(epoch));
+        let _ = fs::remove_file(self.journal_path(epoch));
+    }
+
+    pub(crate) fn sync_metadir(&self) -> SDBResult<()> {
+        Ok(File::open(&self.metadir)?.sync_all()?)
+    }
+
+    pub(crate) fn load_serializable<T: DeserializeOwned>(path: &str) -> SDBResult<T> {
         let mut ser = vec![];
 
         let mut reader = Self::new_read_io(path)?;
@@ -95,13 +380,56 @@ where
         Ok(bincode::deserialize(&ser)?)
     }
 
-    fn persist_serializable(path: &str, object: &impl Serialize) -> SDBResult<()> {
-        let ser = bincode::serialize(object)?;
+    /// Loads a sealed object, checking that it matches the digest it was persisted with and that
+    /// it decrypts under the epoch's key.
+    pub(crate) fn load_sealed<T: DeserializeOwned>(
+        path: &str,
+        name: &str,
+        digest: &[u8; 32],
+        metadata: &MetadataCipher,
+    ) -> SDBResult<T> {
+        let mut sealed = vec![];
+
+        let mut reader = Self::new_read_io(path)?;
+        reader.read_to_end(&mut sealed)?;
 
-        let mut writer = Self::new_write_io(path)?;
-        writer.write_all(&ser)?;
+        if Sha256::digest(&sealed).as_slice() != digest {
+            return Err(Error::Integrity(path.into()));
+        }
 
-        Ok(())
+        let ser = metadata.open(name, &sealed).ok_or_else(|| {
+            Error::Integrity(format!("{path} doesn't decrypt under the epoch's key"))
+        })?;
+        Ok(bincode::deserialize(&ser)?)
+    }
+
+    /// Atomically persists an object, returning the digest of its serialization.
+    pub(crate) fn persist_serializable(path: &str, object: &impl Serialize) -> SDBResult<[u8; 32]> {
+        Self::persist_bytes(path, &bincode::serialize(object)?)
+    }
+
+    /// Atomically persists an object sealed under an epoch's key, returning the digest of the
+    /// sealed blob.
+    pub(crate) fn persist_sealed(
+        path: &str,
+        name: &str,
+        object: &impl Serialize,
+        metadata: &MetadataCipher,
+    ) -> SDBResult<[u8; 32]> {
+        let ser = Zeroizing::new(bincode::serialize(object)?);
+        Self::persist_bytes(path, &metadata.seal(name, &ser))
+    }
+
+    /// Atomically persists raw bytes, returning their digest.
+    pub(crate) fn persist_bytes(path: &str, bytes: &[u8]) -> SDBResult<[u8; 32]> {
+        // Write to a temporary file first so a crash never leaves a partially written object.
+        let tmp = format!("{path}.tmp");
+        let mut writer = FromStd::new(File::create(&tmp)?);
+        writer.write_all(bytes)?;
+        writer.inner().sync_all()?;
+        fs::rename(tmp, path)?;
+
+        Ok(Sha256::digest(bytes).into())
     }
 
     pub fn new_read_io(path: &str) -> SDBResult<FromStd<File>> {
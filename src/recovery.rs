@@ -0,0 +1,176 @@
+use crate::{enclave::Enclave, error::Error, CipherName, SDBResult, SDBTreeFs};
+use allocator::Allocator;
+use crypter::Crypter;
+use log::{info, warn};
+use rand::{CryptoRng, RngCore};
+use sdbtree::storage::Storage;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, Metadata},
+    io,
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+/// A link count that disagrees with the mappings or the datadir.
+#[derive(Debug)]
+pub struct LinkMismatch {
+    pub id: u64,
+    /// The link count we had recorded.
+    pub recorded: u64,
+    /// The number of mapped paths that exist in the datadir.
+    pub mapped: u64,
+    /// The on-disk link count of the ID's regular files, if it has any.
+    pub nlink: Option<u64>,
+}
+
+/// Inconsistencies found between the datadir, mappings, links, and key tree.
+#[derive(Debug, Default)]
+pub struct Reconciliation {
+    /// Files in the datadir without a mapping. These can't be decrypted, so they're only reported.
+    pub unmapped: Vec<String>,
+    /// Mappings that point at missing files.
+    pub dangling: Vec<String>,
+    /// IDs whose link counts disagree with the mappings or the datadir.
+    pub links: Vec<LinkMismatch>,
+    /// Allocated IDs that no mapped path refers to, along with any keys they still have.
+    pub orphaned: Vec<u64>,
+    /// Whether the inconsistencies (besides unmapped files) were repaired.
+    pub repaired: bool,
+}
+
+impl Reconciliation {
+    pub fn is_clean(&self) -> bool {
+        self.unmapped.is_empty()
+            && self.dangling.is_empty()
+            && self.links.is_empty()
+            && self.orphaned.is_empty()
+    }
+
+    pub fn log(&self) {
+        if self.is_clean() {
+            info!("reconciliation: state is consistent");
+            return;
+        }
+
+        let action = if self.repaired { "repaired" } else { "found" };
+
+        for path in &self.unmapped {
+            warn!("reconciliation: found file without a mapping (undecryptable): {path}");
+        }
+        for path in &self.dangling {
+            warn!("reconciliation: {action} mapping to missing file: {path}");
+        }
+        for mismatch in &self.links {
+            warn!(
+                "reconciliation: {action} link count mismatch for id {}: recorded = {}, mapped = {}, nlink = {:?}",
+                mismatch.id, mismatch.recorded, mismatch.mapped, mismatch.nlink
+            );
+        }
+        for id in &self.orphaned {
+            warn!("reconciliation: {action} orphaned id with keys: {id}");
+        }
+    }
+}
+
+impl<A, R, S, C, E, const KEY_SZ: usize, const BLOCK_SZ: usize>
+    SDBTreeFs<A, R, S, C, E, KEY_SZ, BLOCK_SZ>
+where
+    for<'de> A: Allocator<Id = u64> + Default + Serialize + Deserialize<'de> + 'static,
+    R: RngCore + CryptoRng + Default + 'static,
+    S: Storage<Id = u64> + 'static,
+    C: Crypter + CipherName + 'static,
+    E: Enclave<KEY_SZ> + 'static,
+{
+    /// Compares the datadir against the mappings and links, optionally repairing what it can.
+    ///
+    /// The key tree is committed in the same epoch as the block sets and extents that say which
+    /// keys each ID has, so it can't disagree with them, and orphaned IDs get the keys they
+    /// recorded removed.
+    pub fn reconcile(&mut self, repair: bool) -> SDBResult<Reconciliation> {
+        let mut files = HashMap::new();
+        Self::walk(Path::new(&self.canonicalize("/")), &mut files)?;
+
+        let mut report = Reconciliation {
+            repaired: repair,
+            ..Default::default()
+        };
+
+        report.unmapped = files
+            .keys()
+            .filter(|path| !self.mappings.contains_key(*path))
+            .cloned()
+            .collect();
+
+        report.dangling = self
+            .mappings
+            .keys()
+            .filter(|path| !files.contains_key(*path))
+            .cloned()
+            .collect();
+
+        // Count the live paths for each ID, and how many of them are hard links to a regular file.
+        let mut mapped = HashMap::<u64, u64>::new();
+        let mut hard = HashMap::<u64, (u64, u64)>::new();
+        for (path, id) in &self.mappings {
+            if let Some(metadata) = files.get(path) {
+                *mapped.entry(*id).or_default() += 1;
+                if metadata.is_file() {
+                    let (count, nlink) = hard.entry(*id).or_default();
+                    *count += 1;
+                    *nlink = metadata.nlink();
+                }
+            }
+        }
+
+        for (&id, &recorded) in &self.links {
+            let live = mapped.get(&id).copied().unwrap_or(0);
+            let nlink = hard.get(&id).map(|(_, nlink)| *nlink);
+
+            if recorded > 0 && live == 0 {
+                report.orphaned.push(id);
+            } else if recorded != live || hard.get(&id).is_some_and(|(count, nlink)| count != nlink)
+            {
+                report.links.push(LinkMismatch {
+                    id,
+                    recorded,
+                    mapped: live,
+                    nlink,
+                });
+            }
+        }
+
+        if repair {
+            for path in &report.dangling {
+                self.mappings.remove(path);
+            }
+
+            for mismatch in &report.links {
+                self.links.insert(mismatch.id, mismatch.mapped);
+            }
+
+            for &id in &report.orphaned {
+                self.links.remove(&id);
+                self.allocator.dealloc(id).map_err(|_| Error::Dealloc(id))?;
+                self.remove_keys(id)?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn walk(dir: &Path, files: &mut HashMap<String, Metadata>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+
+            if metadata.is_dir() {
+                Self::walk(&entry.path(), files)?;
+            } else if metadata.is_file() || metadata.is_symlink() {
+                files.insert(entry.path().to_string_lossy().to_string(), metadata);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use hkdf::Hkdf;
+use rand::{CryptoRng, RngCore};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+const NONCE_SZ: usize = 12;
+const TAG_SZ: usize = 16;
+
+/// Seals data with AES-256-GCM, laying each sealed blob out as `[nonce][ciphertext][tag]`.
+///
+/// Every use gets its own key, derived from a root key with a per-purpose info string, and binds
+/// blobs to where they belong through the associated data it passes in.
+pub(crate) struct Sealer {
+    cipher: Aes256Gcm,
+}
+
+impl Sealer {
+    /// How much longer a sealed blob is than what it holds.
+    pub const OVERHEAD: usize = NONCE_SZ + TAG_SZ;
+
+    /// Derives the key for one purpose from a root key.
+    pub fn derive(root_key: &[u8], info: &[u8]) -> Self {
+        let mut key = Zeroizing::new([0; 32]);
+        Hkdf::<Sha256>::new(None, root_key)
+            .expand(info, key.as_mut_slice())
+            .unwrap();
+        Self::new(&key)
+    }
+
+    /// Uses a key as is, for keys that are already only used for sealing.
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(key.into()),
+        }
+    }
+
+    pub fn seal(&self, plain: &[u8], aad: &[u8], rng: &mut (impl RngCore + CryptoRng)) -> Vec<u8> {
+        let mut nonce = [0; NONCE_SZ];
+        rng.fill_bytes(&mut nonce);
+
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: plain, aad })
+            .unwrap();
+
+        [nonce.as_slice(), &ciphertext].concat()
+    }
+
+    /// Decrypts a sealed blob, returning `None` if it's torn, has been tampered with, or was
+    /// sealed with different associated data.
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+        if sealed.len() < Self::OVERHEAD {
+            return None;
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SZ);
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+            .map(Zeroizing::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn opens_what_it_seals() {
+        let sealer = Sealer::derive(&[1; 32], b"test");
+        let sealed = sealer.seal(b"plain", b"aad", &mut OsRng);
+        assert_eq!(sealed.len(), 5 + Sealer::OVERHEAD);
+        assert_eq!(sealer.open(&sealed, b"aad").unwrap().as_slice(), b"plain");
+    }
+
+    #[test]
+    fn refuses_tampered_or_misplaced_blobs() {
+        let sealer = Sealer::derive(&[1; 32], b"test");
+        let sealed = sealer.seal(b"plain", b"aad", &mut OsRng);
+
+        assert!(sealer.open(&sealed, b"other").is_none());
+        assert!(sealer
+            .open(&sealed[..Sealer::OVERHEAD - 1], b"aad")
+            .is_none());
+        for i in [0, NONCE_SZ, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(sealer.open(&tampered, b"aad").is_none());
+        }
+
+        // Keys for different purposes can't open each other's blobs.
+        assert!(Sealer::derive(&[1; 32], b"other")
+            .open(&sealed, b"aad")
+            .is_none());
+    }
+}
@@ -0,0 +1,67 @@
+use log::warn;
+use std::{
+    mem,
+    ops::{Deref, DerefMut},
+    sync::Once,
+};
+use zeroize::Zeroize;
+
+/// Key material that's kept in locked memory, so it can't be swapped out, and wiped when it's
+/// dropped.
+///
+/// The value lives on the heap so it never moves once it's locked. Pages are locked as a whole and
+/// can be shared with other secrets, so they're left locked after the value is wiped.
+pub struct Secret<T: Zeroize> {
+    inner: Box<T>,
+}
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        let inner = Box::new(value);
+        lock(&*inner);
+        Self { inner }
+    }
+
+    /// Locks the value again, since locks aren't inherited by a forked child.
+    pub fn relock(&self) {
+        lock(&*self.inner);
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for Secret<T> {
+    fn clone(&self) -> Self {
+        Self::new((*self.inner).clone())
+    }
+}
+
+impl<T: Zeroize> Deref for Secret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: Zeroize> DerefMut for Secret<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.inner.zeroize();
+    }
+}
+
+fn lock<T>(value: &T) {
+    static WARN: Once = Once::new();
+
+    // Locking can fail under a low RLIMIT_MEMLOCK, which shouldn't stop the filesystem, but should
+    // be known about.
+    let ret = unsafe { libc::mlock(value as *const T as *const _, mem::size_of::<T>()) };
+    if ret < 0 {
+        let err = std::io::Error::last_os_error();
+        WARN.call_once(|| warn!("couldn't lock key material in memory: {err}"));
+    }
+}
@@ -0,0 +1,227 @@
+use crate::error::{Error, Result};
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use zeroize::{Zeroize, Zeroizing};
+
+const SHARE_MAGIC: [u8; 8] = *b"SDBTSHAR";
+
+/// One share of a secret split with Shamir's scheme over GF(2^8).
+///
+/// Any `threshold` shares from the same split reconstruct the secret, and fewer reveal nothing
+/// about it. Shares carry the ID of their split so shares from different splits can't be mixed.
+#[derive(Serialize, Deserialize)]
+pub struct Share {
+    magic: [u8; 8],
+    split: [u8; 16],
+    threshold: u8,
+    x: u8,
+    y: Vec<u8>,
+}
+
+impl Share {
+    pub fn index(&self) -> u8 {
+        self.x
+    }
+
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn from_bytes(raw: &[u8]) -> Result<Self> {
+        let share: Self = bincode::deserialize(raw)?;
+        if share.magic != SHARE_MAGIC {
+            return Err(Error::Shares("not a share".into()));
+        }
+        Ok(share)
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.y.zeroize();
+    }
+}
+
+/// Splits a secret into `shares` shares, any `threshold` of which reconstruct it.
+pub fn split(
+    secret: &[u8],
+    threshold: u8,
+    shares: u8,
+    rng: &mut (impl RngCore + CryptoRng),
+) -> Result<Vec<Share>> {
+    if threshold < 2 || threshold > shares {
+        return Err(Error::Shares(format!(
+            "can't split into {shares} shares with a threshold of {threshold}"
+        )));
+    }
+
+    let mut split = [0; 16];
+    rng.fill_bytes(&mut split);
+
+    // Every byte of the secret gets its own random polynomial with the byte as its constant term.
+    let mut coefficients = Zeroizing::new(vec![0; threshold as usize - 1]);
+    let mut ys = vec![Zeroizing::new(Vec::with_capacity(secret.len())); shares as usize];
+    for &byte in secret {
+        rng.fill_bytes(&mut coefficients);
+        for (x, y) in (1..=shares).zip(&mut ys) {
+            // Horner's method, from the highest coefficient down.
+            let term = coefficients
+                .iter()
+                .rev()
+                .fold(0, |acc, &coefficient| mul(acc, x) ^ coefficient);
+            y.push(mul(term, x) ^ byte);
+        }
+    }
+
+    Ok((1..=shares)
+        .zip(ys)
+        .map(|(x, y)| Share {
+            magic: SHARE_MAGIC,
+            split,
+            threshold,
+            x,
+            y: y.to_vec(),
+        })
+        .collect())
+}
+
+/// Reconstructs a secret from at least a threshold of its shares.
+pub fn combine(shares: &[Share]) -> Result<Zeroizing<Vec<u8>>> {
+    let Some(first) = shares.first() else {
+        return Err(Error::Shares("no shares given".into()));
+    };
+    if shares.iter().any(|share| {
+        share.split != first.split
+            || share.threshold != first.threshold
+            || share.y.len() != first.y.len()
+    }) {
+        return Err(Error::Shares("shares are from different splits".into()));
+    }
+
+    // Only a threshold of distinct shares is needed, any more are redundant.
+    let mut used: Vec<&Share> = vec![];
+    for share in shares {
+        if !used.iter().any(|used| used.x == share.x) {
+            used.push(share);
+        }
+    }
+    if used.len() < first.threshold as usize {
+        return Err(Error::Shares(format!(
+            "{} distinct shares given, but {} are needed",
+            used.len(),
+            first.threshold
+        )));
+    }
+    used.truncate(first.threshold as usize);
+
+    // Lagrange interpolation at zero. Subtraction is XOR in GF(2^8).
+    let mut secret = Zeroizing::new(vec![0; first.y.len()]);
+    for (i, share) in used.iter().enumerate() {
+        let basis = used
+            .iter()
+            .enumerate()
+            .filter(|&(j, _)| j != i)
+            .fold(1, |acc, (_, other)| {
+                mul(acc, mul(other.x, inv(other.x ^ share.x)))
+            });
+        for (byte, &y) in secret.iter_mut().zip(&share.y) {
+            *byte ^= mul(y, basis);
+        }
+    }
+
+    Ok(secret)
+}
+
+/// Multiplication in GF(2^8) modulo the AES polynomial, without data-dependent branches.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg();
+        let carry = (a >> 7).wrapping_neg();
+        a = (a << 1) ^ (carry & 0x1b);
+        b >>= 1;
+    }
+    product
+}
+
+/// The multiplicative inverse in GF(2^8), as `a^254`.
+fn inv(a: u8) -> u8 {
+    let mut result = 1;
+    let mut power = a;
+    for bit in 0..8 {
+        if 254 >> bit & 1 == 1 {
+            result = mul(result, power);
+        }
+        power = mul(power, power);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    const SECRET: &[u8] = b"a secret that's longer than a block";
+
+    fn pick(shares: &[Share], xs: &[u8]) -> Vec<Share> {
+        xs.iter()
+            .map(|&x| Share::from_bytes(&shares[x as usize - 1].to_bytes().unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn combines_any_threshold_of_shares() {
+        let shares = split(SECRET, 3, 5, &mut OsRng).unwrap();
+        for xs in [[1, 2, 3], [1, 3, 5], [5, 4, 2], [2, 4, 5]] {
+            assert_eq!(*combine(&pick(&shares, &xs)).unwrap(), SECRET);
+        }
+    }
+
+    #[test]
+    fn combines_more_than_a_threshold_of_shares() {
+        let shares = split(SECRET, 2, 5, &mut OsRng).unwrap();
+        assert_eq!(*combine(&shares).unwrap(), SECRET);
+        assert_eq!(*combine(&pick(&shares, &[4, 1, 3])).unwrap(), SECRET);
+    }
+
+    #[test]
+    fn duplicate_shares_only_count_once() {
+        let shares = split(SECRET, 3, 5, &mut OsRng).unwrap();
+        assert!(combine(&pick(&shares, &[1, 1, 2])).is_err());
+        assert_eq!(*combine(&pick(&shares, &[1, 1, 2, 4])).unwrap(), SECRET);
+    }
+
+    #[test]
+    fn refuses_too_few_shares() {
+        let shares = split(SECRET, 3, 5, &mut OsRng).unwrap();
+        assert!(combine(&pick(&shares, &[2, 5])).is_err());
+        assert!(combine(&[]).is_err());
+    }
+
+    #[test]
+    fn refuses_shares_from_different_splits() {
+        let first = split(SECRET, 2, 3, &mut OsRng).unwrap();
+        let second = split(SECRET, 2, 3, &mut OsRng).unwrap();
+        let mut mixed = pick(&first, &[1]);
+        mixed.extend(pick(&second, &[2]));
+        assert!(combine(&mixed).is_err());
+    }
+
+    #[test]
+    fn refuses_impossible_thresholds() {
+        assert!(split(SECRET, 1, 3, &mut OsRng).is_err());
+        assert!(split(SECRET, 4, 3, &mut OsRng).is_err());
+    }
+
+    #[test]
+    fn inverts_every_element() {
+        for a in 1..=255 {
+            assert_eq!(mul(a, inv(a)), 1);
+        }
+    }
+}
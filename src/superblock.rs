@@ -0,0 +1,283 @@
+use crate::{
+    blocks::BlockSet,
+    enclave::Enclave,
+    error::Error,
+    localize::Extents,
+    localizer::{Localization, Localizer, Packed},
+    CipherName, Key, SDBResult, SDBTreeFs,
+};
+use allocator::Allocator;
+use crypter::Crypter;
+use log::{info, warn};
+use rand::{CryptoRng, RngCore};
+use sdbtree::storage::Storage;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    path::Path,
+};
+use zeroize::Zeroizing;
+
+const MAGIC: [u8; 8] = *b"SDBTREFS";
+
+/// The current on-disk format version.
+///
+/// - Version 0 is the original layout: bare `links`, `mappings`, `allocator`, and `root` files
+///   with no superblock or manifest, and the root key raw at the start of the enclave.
+/// - Version 1 commits sealed metadata for each epoch through a MAC'd manifest, with the root
+///   key and epoch in alternating enclave slots, per-file block sets and extent tables, and the
+///   key localization strategy in the superblock.
+const VERSION: u32 = 1;
+
+/// Records the format version and the parameters a volume was created with.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Superblock {
+    magic: [u8; 8],
+    version: u32,
+    key_size: usize,
+    block_size: usize,
+    cipher: String,
+    iv_size: usize,
+    /// The key tree's degree, or `None` for sdbtree's default, which original volumes used.
+    degree: Option<usize>,
+    localization: Localization,
+}
+
+impl<A, R, S, C, E, const KEY_SZ: usize, const BLOCK_SZ: usize>
+    SDBTreeFs<A, R, S, C, E, KEY_SZ, BLOCK_SZ>
+where
+    for<'de> A: Allocator<Id = u64> + Default + Serialize + Deserialize<'de> + 'static,
+    R: RngCore + CryptoRng + Default + 'static,
+    S: Storage<Id = u64> + 'static,
+    C: Crypter + CipherName + 'static,
+    E: Enclave<KEY_SZ> + 'static,
+{
+    pub(crate) fn superblock_path(&self) -> String {
+        format!("{}/superblock", self.metadir)
+    }
+
+    fn superblock(&self) -> Superblock {
+        Superblock {
+            magic: MAGIC,
+            version: VERSION,
+            key_size: KEY_SZ,
+            block_size: BLOCK_SZ,
+            cipher: C::NAME.into(),
+            iv_size: C::iv_length(),
+            degree: self.degree,
+            localization: self.localization,
+        }
+    }
+
+    fn degree_name(degree: Option<usize>) -> String {
+        degree.map_or_else(|| "sdbtree's default".into(), |degree| degree.to_string())
+    }
+
+    pub(crate) fn persist_superblock(&self) -> SDBResult<()> {
+        Self::persist_serializable(&self.superblock_path(), &self.superblock())?;
+        self.sync_metadir()
+    }
+
+    /// Checks that the volume's superblock matches our parameters, migrating older volumes to the
+    /// current version first.
+    pub(crate) fn check_superblock(&mut self) -> SDBResult<()> {
+        let mut superblock = if Path::new(&self.superblock_path()).exists() {
+            Self::load_serializable::<Superblock>(&self.superblock_path())?
+        } else if Path::new(&self.legacy_root_path()).exists() {
+            // Original volumes localized keys by their file's ID, which is what packing does, and
+            // built their trees with sdbtree's default degree.
+            Superblock {
+                version: 0,
+                localization: Localization::Packed,
+                degree: None,
+                ..self.superblock()
+            }
+        } else {
+            return Err(Error::Superblock("missing superblock".into()));
+        };
+
+        if superblock.magic != MAGIC {
+            return Err(Error::Superblock("bad magic number".into()));
+        }
+
+        if superblock.version > VERSION {
+            return Err(Error::Superblock(format!(
+                "unsupported version {} (newest supported is {VERSION})",
+                superblock.version
+            )));
+        }
+
+        let expected = self.superblock();
+        if superblock.key_size != expected.key_size {
+            return Err(Error::Superblock(format!(
+                "key size mismatch: volume uses {}, expected {}",
+                superblock.key_size, expected.key_size
+            )));
+        }
+        if superblock.block_size != expected.block_size {
+            return Err(Error::Superblock(format!(
+                "block size mismatch: volume uses {}, expected {}",
+                superblock.block_size, expected.block_size
+            )));
+        }
+        if superblock.cipher != expected.cipher {
+            return Err(Error::Superblock(format!(
+                "cipher mismatch: volume uses {}, expected {}",
+                superblock.cipher, expected.cipher
+            )));
+        }
+        if superblock.iv_size != expected.iv_size {
+            return Err(Error::Superblock(format!(
+                "IV size mismatch: volume uses {}, expected {}",
+                superblock.iv_size, expected.iv_size
+            )));
+        }
+        if superblock.degree != expected.degree {
+            return Err(Error::Superblock(format!(
+                "degree mismatch: volume uses {}, expected {}",
+                Self::degree_name(superblock.degree),
+                Self::degree_name(expected.degree)
+            )));
+        }
+        if superblock.localization != expected.localization {
+            return Err(Error::Superblock(format!(
+                "localization mismatch: volume uses {}, expected {}",
+                superblock.localization, expected.localization
+            )));
+        }
+
+        while superblock.version < VERSION {
+            info!(
+                "migrating volume from version {} to {}",
+                superblock.version,
+                superblock.version + 1
+            );
+            self.migrate(superblock.version)?;
+            superblock.version += 1;
+            Self::persist_serializable(&self.superblock_path(), &superblock)?;
+            self.sync_metadir()?;
+        }
+
+        Ok(())
+    }
+
+    /// Upgrades the metadir from `version` to `version + 1`.
+    ///
+    /// Layout changes should bump `VERSION` and add a migration here from the previous version.
+    fn migrate(&mut self, version: u32) -> SDBResult<()> {
+        match version {
+            0 => self.migrate_legacy(),
+            _ => Err(Error::Superblock(format!(
+                "no migration from version {version}"
+            ))),
+        }
+    }
+
+    /// Whether the metadir already holds a volume, in either layout.
+    pub(crate) fn is_formatted(&self) -> bool {
+        [
+            self.superblock_path(),
+            self.manifest_path(0),
+            self.manifest_path(1),
+            self.legacy_root_path(),
+        ]
+        .iter()
+        .any(|path| Path::new(path).exists())
+    }
+
+    fn legacy_root_path(&self) -> String {
+        format!("{}/root", self.metadir)
+    }
+
+    fn legacy_paths(&self) -> [String; 4] {
+        ["links", "mappings", "allocator", "root"].map(|name| format!("{}/{name}", self.metadir))
+    }
+
+    /// Reads the root key from the start of the enclave, where the original layout keeps it.
+    fn read_legacy_root_key(&mut self) -> SDBResult<Zeroizing<Key<KEY_SZ>>> {
+        let enclave = self.enclave.legacy_file().ok_or(Error::Enclave)?;
+        let mut root_key = Zeroizing::new([0; KEY_SZ]);
+        enclave.seek(SeekFrom::Start(0))?;
+        enclave
+            .read_exact(root_key.as_mut_slice())
+            .map_err(|_| Error::Enclave)?;
+        Ok(root_key)
+    }
+
+    /// Loads the original layout and commits it as the first epoch of the current one.
+    ///
+    /// Every block up to the end of a file is recorded as having a key, and each file gets its
+    /// own ID as its first extent, which is where its keys were localized. Keys past the first
+    /// extent, or of IDs too large to be an extent, wrapped onto other keys, so those blocks
+    /// become holes.
+    ///
+    /// The bare files are only removed once the new epoch is committed, though their contents may
+    /// linger on disk until the blocks they used are reused.
+    fn migrate_legacy(&mut self) -> SDBResult<()> {
+        // The raw root key overlaps the first slot without decoding as a record, so a record
+        // means we crashed after the commit below, and only the cleanup is left.
+        if self.enclave.get()?.is_none() {
+            let root_key = self.read_legacy_root_key()?;
+            let [links, mappings, allocator, root] = self.legacy_paths();
+            let root_id = Self::load_serializable(&root)?;
+            self.links = Self::load_serializable(&links)?;
+            self.mappings = Self::load_serializable(&mappings)?;
+            self.allocator = Self::load_serializable(&allocator)?;
+
+            let mut blocks = HashMap::<u64, BlockSet>::new();
+            for (path, &id) in &self.mappings {
+                if let Ok(metadata) = fs::metadata(path) {
+                    if metadata.is_file() {
+                        let size = Self::unpadded_size(metadata.len());
+                        blocks
+                            .entry(id)
+                            .or_default()
+                            .insert(0..size.div_ceil(BLOCK_SZ as u64));
+                    }
+                }
+            }
+
+            let mut extents = HashMap::<u64, Extents>::new();
+            blocks.retain(|&id, blocks| {
+                if id > Packed.max_extent() {
+                    warn!("dropping the keys of id {id}, which collided with other keys");
+                    return false;
+                }
+                if blocks
+                    .ranges()
+                    .any(|range| range.end > Packed.extent_blocks())
+                {
+                    warn!(
+                        "dropping the keys of id {id} past its first extent, which wrapped around"
+                    );
+                    blocks.remove(Packed.extent_blocks()..u64::MAX);
+                }
+                extents.insert(id, Extents::from([(0, id)]));
+                true
+            });
+            self.blocks = blocks;
+            self.extents = extents;
+
+            self.tree
+                .load(root_id, *root_key)
+                .map_err(|_| Error::Storage)?;
+            self.epoch = 0;
+            self.root_id = root_id;
+            *self.root_key = *root_key;
+
+            self.persist()?;
+        }
+
+        // The superblock goes first, since the legacy root file is what marks an original volume.
+        self.persist_superblock()?;
+        for path in self.legacy_paths() {
+            match fs::remove_file(path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        self.sync_metadir()
+    }
+}
@@ -1,11 +1,41 @@
-use super::Key;
+use super::{secret::Secret, Key};
 use rand::{CryptoRng, RngCore};
+use std::io;
 
-pub fn generate_key<R, const KEY_SZ: usize>(rng: &mut R) -> Key<KEY_SZ>
+/// Generates a key straight into locked memory.
+pub fn generate_key<R, const KEY_SZ: usize>(rng: &mut R) -> Secret<Key<KEY_SZ>>
 where
     R: RngCore + CryptoRng,
 {
-    let mut key = [0; KEY_SZ];
-    rng.fill_bytes(&mut key);
+    let mut key = Secret::new([0; KEY_SZ]);
+    rng.fill_bytes(&mut *key);
     key
 }
+
+/// Locks every page of the process in memory, now and in the future, and makes it undumpable, so
+/// key material can't end up in swap or a core dump.
+///
+/// This has to happen before any keys or passphrases are read to cover them. Locks aren't
+/// inherited by a forked child, so a daemon has to take them again once it's forked.
+pub fn lock_memory() -> io::Result<()> {
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Resets SIGINT and SIGTERM to their default dispositions.
+///
+/// libfuse only installs its own handlers (which cleanly exit the session loop and run
+/// `destroy`) for signals that are at their defaults. A mount started from a non-interactive
+/// shell inherits an ignored SIGINT, so we reset them before mounting.
+pub fn restore_exit_signals() {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+    }
+}